@@ -0,0 +1,37 @@
+//! Arbitrary storage through both engines: `cargo +nightly fuzz run storage_roots`.
+//!
+//! Every key must convert to a 251-bit trie path and every value must round-trip through
+//! `Felt`, without panicking, and Bonsai must agree with the reference trie on the root.
+
+#![no_main]
+
+use std::collections::HashMap;
+
+use deoxys_test::{
+    bonsai_root, reference, to_felt, to_field_element, trie_key, BonsaiOptions, TRIE_KEY_LEN,
+};
+use indicatif::ProgressBar;
+use libfuzzer_sys::fuzz_target;
+use starknet::core::types::FieldElement;
+
+fuzz_target!(|pairs: Vec<([u8; 32], [u8; 32])>| {
+    // Storage keys are below 2^251, values anywhere below the modulus, zero included
+    let storage: HashMap<FieldElement, FieldElement> = pairs
+        .into_iter()
+        .filter_map(|(mut key, value)| {
+            key[0] &= 0x07;
+            let key = FieldElement::from_bytes_be(&key).ok()?;
+            let value = FieldElement::from_bytes_be(&value).ok()?;
+            Some((key, value))
+        })
+        .collect();
+
+    for (key, value) in &storage {
+        assert_eq!(trie_key(key).len(), TRIE_KEY_LEN, "key {key:#x}");
+        assert_eq!(to_field_element(&to_felt(value)), *value);
+    }
+
+    let bonsai = bonsai_root(&storage, &ProgressBar::hidden(), &BonsaiOptions::default())
+        .expect("Bonsai fails on valid storage");
+    assert_eq!(bonsai, reference::root(&storage));
+});
@@ -0,0 +1,153 @@
+//! Synthetic storages built to stress edge compression, where implementations of the
+//! Merkle-Patricia rules are most likely to disagree.
+//!
+//! Values are distinct small integers, except for the near-modulus pattern which covers the
+//! high end of the value space, and the zeros of the phantom-deletes patterns. Keys are
+//! described by the trie path bits they set, bit 0 being the first bit below the root and
+//! bit 250 the last one above the leaves.
+
+use starknet::core::types::FieldElement;
+
+use crate::TRIE_KEY_LEN;
+
+pub struct Pattern {
+    pub name: &'static str,
+    /// What the pattern exercises
+    pub intent: &'static str,
+    pub storage: Vec<(FieldElement, FieldElement)>,
+}
+
+/// Every pattern. The root of a pattern must equal the root of its nonzero values alone,
+/// and be zero if it has none.
+pub fn patterns() -> Vec<Pattern> {
+    let last = TRIE_KEY_LEN - 1;
+
+    vec![
+        pattern(
+            "empty",
+            "no leaves at all, committing a trie without inserts must yield the zero root",
+            vec![],
+        ),
+        pattern(
+            "single-zero-key",
+            "a lone leaf at key 0, the root is one edge spanning the whole height",
+            vec![key(&[])],
+        ),
+        pattern(
+            "single-max-key",
+            "a lone leaf at the largest key, an edge whose path is all ones",
+            vec![key(&(0..TRIE_KEY_LEN).collect::<Vec<_>>())],
+        ),
+        pattern(
+            "split-at-root",
+            "keys differing in their first bit only, a binary root over two 250-bit edges",
+            vec![key(&[]), key(&[0])],
+        ),
+        pattern(
+            "split-at-leaves",
+            "keys differing in their last bit only, a 250-bit edge over a binary node of leaves",
+            vec![key(&[]), key(&[last])],
+        ),
+        pattern(
+            "long-shared-prefix",
+            "keys sharing an all-ones 200-bit prefix, then splitting into two branches",
+            vec![
+                key(&(0..200).collect::<Vec<_>>()),
+                key(&(0..=200).collect::<Vec<_>>()),
+                key(&(0..200).chain([230]).collect::<Vec<_>>()),
+            ],
+        ),
+        pattern(
+            "one-hot",
+            "one key per set bit, every binary node has an edge of a different length below it",
+            (0..TRIE_KEY_LEN).map(|bit| key(&[bit])).collect(),
+        ),
+        pattern(
+            "dense-bottom",
+            "keys 0 to 255, a complete binary subtrie of depth 8 under a 243-bit edge",
+            (0..256u64).map(FieldElement::from).collect(),
+        ),
+        pattern(
+            "sparse-pairs",
+            "pairs of keys one bit apart scattered along the height, mixing edge lengths",
+            (0..TRIE_KEY_LEN)
+                .step_by(25)
+                .flat_map(|bit| [key(&[bit]), key(&[bit, last])])
+                .collect(),
+        ),
+        Pattern {
+            name: "phantom-deletes",
+            intent: "zero writes to keys never inserted, a no-op which must leave the empty root",
+            storage: phantom_deletes(&[key(&[]), key(&[0]), key(&[last])]),
+        },
+        Pattern {
+            name: "mixed-phantom-deletes",
+            intent: "inserts interleaved with zero writes to fresh keys, which must not change the root",
+            storage: vec![
+                (key(&[]), FieldElement::ONE),
+                (key(&[1]), FieldElement::ZERO),
+                (key(&[0]), FieldElement::from(2u64)),
+                (key(&[0, last]), FieldElement::ZERO),
+            ],
+        },
+        Pattern {
+            name: "near-modulus",
+            intent: "values at and just below the field modulus, where a lossy conversion would truncate",
+            storage: near_modulus_values()
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| (key(&[i * 31]), value))
+                .collect(),
+        },
+    ]
+}
+
+/// Zero values for `keys`, i.e. deletions of keys which need not exist.
+fn phantom_deletes(keys: &[FieldElement]) -> Vec<(FieldElement, FieldElement)> {
+    keys.iter().map(|key| (*key, FieldElement::ZERO)).collect()
+}
+
+/// The largest felt, `p - 1`, and a few values just below it.
+pub fn near_modulus_values() -> Vec<FieldElement> {
+    let max = FieldElement::MAX;
+    let two_to = |bits: u32| {
+        let mut bytes = [0u8; 32];
+        bytes[31 - bits as usize / 8] = 1 << (bits % 8);
+        FieldElement::from_bytes_be(&bytes).unwrap()
+    };
+
+    vec![
+        max,
+        max - FieldElement::ONE,
+        max - FieldElement::from(2u64),
+        max - FieldElement::from(u64::MAX),
+        max - two_to(128),
+        max - two_to(192),
+    ]
+}
+
+fn pattern(name: &'static str, intent: &'static str, keys: Vec<FieldElement>) -> Pattern {
+    let storage = keys
+        .into_iter()
+        .zip(1u64..)
+        .map(|(key, value)| (key, FieldElement::from(value)))
+        .collect();
+
+    Pattern {
+        name,
+        intent,
+        storage,
+    }
+}
+
+/// Key whose trie path has exactly `bits` set.
+fn key(bits: &[usize]) -> FieldElement {
+    let mut bytes = [0u8; 32];
+    for &bit in bits {
+        // Trie paths skip the 5 leading bits of the 256-bit big-endian felt
+        let bit = bit + 256 - TRIE_KEY_LEN;
+        bytes[bit / 8] |= 0x80 >> (bit % 8);
+    }
+
+    FieldElement::from_bytes_be(&bytes).unwrap()
+}
@@ -0,0 +1,150 @@
+//! Tamper-evident records of a comparison run, for auditors.
+//!
+//! An attestation lists the run's parameters, the versions of the trie and felt
+//! dependencies it was built with, a SHA-256 digest of every per-block result and the
+//! final verdict. It can be signed with an ed25519 key, the signature then covering the
+//! compact JSON serialization of the attestation:
+//!
+//! ```json
+//! { "attestation": { .. }, "signature": "ab12..", "public_key": "cd34.." }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use ed25519_dalek::{Signer, SigningKey};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::BlockResult;
+
+/// Dependencies whose version decides the roots, as locked when this binary was built.
+const ATTESTED_DEPENDENCIES: &[&str] = &["bonsai-trie", "starknet", "starknet-types-core"];
+
+#[derive(Serialize)]
+pub struct Attestation {
+    pub tool_version: &'static str,
+    pub dependencies: BTreeMap<&'static str, String>,
+    pub parameters: serde_json::Value,
+    pub blocks: u64,
+    /// SHA-256 of one `block,root,mismatch` line per computed root, in block order
+    pub results_sha256: String,
+    pub passed: bool,
+}
+
+#[derive(Serialize)]
+struct Signed<'a> {
+    attestation: &'a Attestation,
+    signature: String,
+    public_key: String,
+}
+
+/// Digest of per-block results, fed with every computed root as the run goes.
+#[derive(Default)]
+pub struct ResultsDigest(Sha256);
+
+impl ResultsDigest {
+    pub fn update(&mut self, result: &BlockResult) {
+        let line = format!(
+            "{},{:#x},{}\n",
+            result.block, result.root.root, result.mismatch
+        );
+        self.0.update(line.as_bytes());
+    }
+
+    pub fn finish(self) -> String {
+        hex(&self.0.finalize())
+    }
+}
+
+impl Attestation {
+    pub fn new(
+        parameters: serde_json::Value,
+        blocks: u64,
+        digest: ResultsDigest,
+        passed: bool,
+    ) -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION"),
+            dependencies: locked_versions(),
+            parameters,
+            blocks,
+            results_sha256: digest.finish(),
+            passed,
+        }
+    }
+
+    /// Writes the attestation to `path`, signed with the ed25519 key in `key_path` if
+    /// given. The key file holds the 32-byte secret key in hex.
+    pub fn write(&self, path: &Path, key_path: Option<&Path>) -> anyhow::Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create attestation file {}", path.display()))?;
+        let writer = BufWriter::new(file);
+
+        let written = match key_path {
+            Some(key_path) => {
+                let key = read_signing_key(key_path)?;
+                let message = serde_json::to_vec(self)?;
+                let signed = Signed {
+                    attestation: self,
+                    signature: hex(&key.sign(&message).to_bytes()),
+                    public_key: hex(key.verifying_key().as_bytes()),
+                };
+                serde_json::to_writer_pretty(writer, &signed)
+            }
+            None => serde_json::to_writer_pretty(writer, self),
+        };
+        written.with_context(|| format!("Failed to write attestation to {}", path.display()))
+    }
+}
+
+fn read_signing_key(path: &Path) -> anyhow::Result<SigningKey> {
+    let key = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read signing key {}", path.display()))?;
+    let key = key.trim().trim_start_matches("0x");
+
+    let mut secret = [0u8; 32];
+    if key.len() != 64 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!(
+            "Signing key {} must be 32 bytes of hex",
+            path.display()
+        ));
+    }
+    for (byte, digits) in secret.iter_mut().zip(key.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits)?, 16)?;
+    }
+
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// `version (source)` of each of [`ATTESTED_DEPENDENCIES`], read from the lock file.
+pub(crate) fn locked_versions() -> BTreeMap<&'static str, String> {
+    let lock = include_str!("../Cargo.lock");
+    let mut versions = BTreeMap::new();
+
+    for package in lock.split("[[package]]") {
+        let field = |name: &str| {
+            package
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(" = "))
+                .map(|value| value.trim_matches('"'))
+        };
+        let Some(name) = field("name") else {
+            continue;
+        };
+        if let Some(&name) = ATTESTED_DEPENDENCIES.iter().find(|&&d| d == name) {
+            let version = field("version").unwrap_or("?");
+            let source = field("source").unwrap_or("crates.io");
+            versions.insert(name, format!("{version} ({source})"));
+        }
+    }
+
+    versions
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,161 @@
+//! The verdict of a full comparison run over a contract, as a report to attach to an audit.
+//!
+//! The same report renders for the terminal and as markdown.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::ops::Range;
+
+use starknet::core::types::FieldElement;
+use starknet_types_core::felt::Felt;
+
+use crate::attestation::locked_versions;
+use crate::RootFormat;
+
+pub struct Report {
+    pub contract: FieldElement,
+    pub range: Range<u64>,
+    pub blocks: u64,
+    /// Blocks which touched the contract's storage
+    pub blocks_touched: u64,
+    /// Storage writes over the range, counting a key once per block writing it
+    pub keys_written: u64,
+    /// Distinct keys written over the range
+    pub unique_keys: usize,
+    /// Blocks whose Bonsai and reference roots differed
+    pub mismatches: Vec<u64>,
+    pub bonsai_root: Felt,
+    pub reference_root: Felt,
+    pub dependencies: BTreeMap<&'static str, String>,
+}
+
+impl Report {
+    pub fn new(contract: FieldElement, range: Range<u64>) -> Self {
+        Self {
+            contract,
+            range,
+            blocks: 0,
+            blocks_touched: 0,
+            keys_written: 0,
+            unique_keys: 0,
+            mismatches: Vec::new(),
+            bonsai_root: Felt::ZERO,
+            reference_root: Felt::ZERO,
+            dependencies: locked_versions(),
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty() && self.bonsai_root == self.reference_root
+    }
+
+    fn verdict(&self) -> &'static str {
+        if self.passed() {
+            "PASS"
+        } else {
+            "FAIL"
+        }
+    }
+
+    fn mismatched_blocks(&self) -> String {
+        if self.mismatches.is_empty() {
+            return "none".to_string();
+        }
+        let blocks: Vec<_> = self.mismatches.iter().map(u64::to_string).collect();
+        blocks.join(", ")
+    }
+
+    pub fn terminal(&self, root_format: RootFormat) -> String {
+        let mut report = String::new();
+        let verdict = if self.passed() { "✅" } else { "❌" };
+
+        writeln!(report, "📜 audit of contract {:#x}", self.contract).unwrap();
+        for (name, version) in &self.dependencies {
+            writeln!(report, "📦 {name} {version}").unwrap();
+        }
+        writeln!(
+            report,
+            "🧱 blocks {}..{}: {} processed, {} touching the contract",
+            self.range.start, self.range.end, self.blocks, self.blocks_touched
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "🔑 {} keys written, {} unique",
+            self.keys_written, self.unique_keys
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "🔍 mismatching blocks: {}",
+            self.mismatched_blocks()
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "🌳 bonsai root:    {}",
+            root_format.format(&self.bonsai_root)
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "🌳 reference root: {}",
+            root_format.format(&self.reference_root)
+        )
+        .unwrap();
+        writeln!(report, "{verdict} {}", self.verdict()).unwrap();
+
+        report
+    }
+
+    pub fn markdown(&self, root_format: RootFormat) -> String {
+        let mut report = String::new();
+
+        writeln!(report, "# Storage root audit of `{:#x}`\n", self.contract).unwrap();
+        writeln!(report, "**Verdict: {}**\n", self.verdict()).unwrap();
+
+        writeln!(report, "## Dependencies\n").unwrap();
+        writeln!(report, "| Crate | Version |\n|---|---|").unwrap();
+        for (name, version) in &self.dependencies {
+            writeln!(report, "| {name} | {version} |").unwrap();
+        }
+
+        writeln!(report, "\n## Run\n").unwrap();
+        writeln!(report, "| | |\n|---|---|").unwrap();
+        writeln!(
+            report,
+            "| Range | {}..{} |",
+            self.range.start, self.range.end
+        )
+        .unwrap();
+        writeln!(report, "| Blocks processed | {} |", self.blocks).unwrap();
+        writeln!(
+            report,
+            "| Blocks touching the contract | {} |",
+            self.blocks_touched
+        )
+        .unwrap();
+        writeln!(report, "| Keys written | {} |", self.keys_written).unwrap();
+        writeln!(report, "| Unique keys | {} |", self.unique_keys).unwrap();
+        writeln!(
+            report,
+            "| Mismatching blocks | {} |",
+            self.mismatched_blocks()
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "| Bonsai root | `{}` |",
+            root_format.format(&self.bonsai_root)
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "| Reference root | `{}` |",
+            root_format.format(&self.reference_root)
+        )
+        .unwrap();
+
+        report
+    }
+}
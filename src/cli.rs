@@ -0,0 +1,787 @@
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use deoxys_test::{LeafTransform, Network, RootFormat, TimeUnit};
+use starknet::core::types::FieldElement;
+use url::Url;
+
+#[derive(Parser, Debug)]
+#[command(
+    about = "Computes Bonsai storage roots for a contract over a range of Starknet blocks",
+    after_help = "--contract, --from, --to and --network can also be set with the \
+        DEOXYS_CONTRACT, DEOXYS_FROM, DEOXYS_TO and DEOXYS_NETWORK environment variables, \
+        e.g. in containers. Flags take precedence over the environment, which takes \
+        precedence over the defaults: --contract-from-tx, --from-time and --to-time \
+        override a contract or block from the environment, and only conflict with \
+        --contract, --from and --to on the command line."
+)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The contract to watch
+    #[arg(
+        long,
+        global = true,
+        env = "DEOXYS_CONTRACT",
+        value_parser = parse_contract_address,
+        default_value = "0x020cfa74ee3564b4cd5435cdace0f9c4d43b939620e4a0bb5076105df0a626c6"
+    )]
+    pub contract: FieldElement,
+
+    /// Watch the contract deployed by this `DEPLOY` or `DEPLOY_ACCOUNT` transaction
+    /// instead of --contract
+    #[arg(long, value_parser = parse_felt)]
+    pub contract_from_tx: Option<FieldElement>,
+
+    /// First block of the range. The range should contain the block at which the
+    /// contract was deployed
+    #[arg(long, global = true, env = "DEOXYS_FROM", default_value_t = 0)]
+    pub from: u64,
+
+    /// End of the range, exclusive. Must be above --from
+    #[arg(long, global = true, env = "DEOXYS_TO", default_value_t = 400)]
+    pub to: u64,
+
+    /// Start the range at the first block produced at or after this unix timestamp,
+    /// instead of --from
+    #[arg(long, value_name = "UNIX_SECONDS")]
+    pub from_time: Option<u64>,
+
+    /// End the range before the first block produced at or after this unix timestamp,
+    /// instead of --to
+    #[arg(long, value_name = "UNIX_SECONDS")]
+    pub to_time: Option<u64>,
+
+    /// Network whose sequencer gateway is queried. The default --contract is a mainnet
+    /// contract
+    #[arg(
+        long,
+        global = true,
+        env = "DEOXYS_NETWORK",
+        value_enum,
+        default_value_t = Network::Mainnet
+    )]
+    pub network: Network,
+
+    /// Free-form label for this run, repeated in the header and the summary line to tell
+    /// outputs of many runs apart
+    #[arg(long, default_value = "")]
+    pub label: String,
+
+    /// Serve run counters in the Prometheus text format on this address, e.g.
+    /// `127.0.0.1:9184`
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Only list the blocks of the range which touch the contract's storage, without
+    /// computing any root
+    #[arg(long)]
+    pub list_only: bool,
+
+    /// Only apply the state update of the block with this hash, instead of the range
+    #[arg(long, value_parser = parse_felt)]
+    pub block_hash: Option<FieldElement>,
+
+    /// Stop the run gracefully once it has been going for this long, e.g. `30m`, `90s` or
+    /// `2h`, still printing the summary and exporting the snapshot of the blocks covered so
+    /// far. The run then exits with code 3, unless it failed, and can be resumed with
+    /// --load-snapshot
+    #[arg(long, value_parser = parse_duration)]
+    pub deadline: Option<Duration>,
+
+    /// Give up on a gateway request after this many milliseconds. Failed requests are
+    /// retried up to 15 times, 5s apart, each attempt with the full timeout
+    #[arg(long, value_name = "MS", default_value_t = 30_000)]
+    pub request_timeout_ms: u64,
+
+    /// Send at most this many requests per second to the gateway. Rate limited responses
+    /// are backed off from regardless
+    #[arg(long, value_parser = parse_rps)]
+    pub rps: Option<f64>,
+
+    /// When a state update fails to fetch or parse, save the gateway's raw response in
+    /// this directory to diagnose format changes
+    #[arg(long, value_name = "DIR")]
+    pub save_raw_on_error: Option<PathBuf>,
+
+    /// Make two runs over the same range print the same output, timings aside, for bug
+    /// reports. This runs everything on a single thread, so that the metrics endpoint and
+    /// concurrency-check tasks interleave identically, and inserts keys in ascending order
+    /// instead of the random iteration order of the accumulated map, which also orders the
+    /// `🔑` lines. Blocks are already fetched one at a time with no prefetch, and Bonsai
+    /// commit ids always start from the same id in every fresh trie
+    #[arg(long)]
+    pub deterministic: bool,
+
+    /// Only insert storage keys whose 64-digit big-endian hex representation starts with
+    /// this prefix (e.g. `0x01`). Keys are below 2^251, so the representation starts
+    /// with `0` and a digit up to `7`. The resulting root covers that subtree alone and
+    /// will not match chain state.
+    #[arg(long, value_parser = parse_key_prefix)]
+    pub key_prefix: Option<String>,
+
+    /// Leave this key out of every trie, whatever its value, to compare only the storage
+    /// which does not change every block. Repeatable. Roots will not match chain state
+    #[arg(long, value_parser = parse_felt, value_name = "KEY")]
+    pub volatile_key: Vec<FieldElement>,
+
+    /// Also recompute every root with the slow built-in reference trie and abort if it
+    /// disagrees with Bonsai
+    #[arg(long)]
+    pub reference_check: bool,
+
+    /// With --reference-check, log mismatches and keep going instead of aborting. The run
+    /// still fails at the end if any block mismatched
+    #[arg(long, requires = "reference_check")]
+    pub no_assert: bool,
+
+    /// Transform applied to every storage value before insertion, identically in every
+    /// trie. Anything but `identity` yields roots which do not match chain state, as of
+    /// Starknet 0.13
+    #[arg(long, value_enum, default_value_t = LeafTransform::Identity)]
+    pub leaf_transform: LeafTransform,
+
+    /// How roots are printed
+    #[arg(long, value_enum, default_value_t = RootFormat::Padded)]
+    pub root_format: RootFormat,
+
+    /// Unit of printed durations, including the --root-timing-csv column
+    #[arg(long, value_enum, default_value_t = TimeUnit::Auto)]
+    pub time_unit: TimeUnit,
+
+    /// Decimals of printed durations. The --root-timing-csv column keeps full precision
+    /// unless this is given
+    #[arg(long, value_name = "DIGITS")]
+    pub time_precision: Option<usize>,
+
+    /// Warn when a single block changes more than this many keys of the watched contract.
+    /// Off by default
+    #[arg(long, value_name = "N")]
+    pub max_keys_per_block: Option<usize>,
+
+    /// Abort instead of warning when --max-keys-per-block is exceeded
+    #[arg(long, requires = "max_keys_per_block")]
+    pub max_keys_error: bool,
+
+    /// Skip the root computation for blocks whose diff leaves the accumulated storage
+    /// unchanged, logging them as no-op blocks instead
+    #[arg(long)]
+    pub skip_noop: bool,
+
+    /// Log for each block whether it changed the root, along with the roots before and
+    /// after it, for both Bonsai and the reference trie. Fails if they disagree on whether
+    /// the root changed, unless --no-assert
+    #[arg(long)]
+    pub root_delta: bool,
+
+    /// JSON file describing the fields packed in the values of some keys, logged whenever
+    /// those keys change. Only affects the logs, roots are computed over raw values
+    #[arg(long, value_name = "PATH")]
+    pub layout: Option<PathBuf>,
+
+    /// Only accumulate storage diffs, not the class hash and nonce of the contract. Storage
+    /// roots are unaffected, but leaf-hash needs the class hash and nonce and refuses this
+    #[arg(long)]
+    pub storage_only: bool,
+
+    /// Log every storage key whose nonzero value is set to zero. Cleared keys are counted
+    /// in the summary either way
+    #[arg(long)]
+    pub log_clears: bool,
+
+    /// Where the progress bar is drawn. Roots, `🔑 key -> value` logs, warnings, mismatches
+    /// and summaries always go to stdout, above the bar wherever it is. `stderr` keeps
+    /// stdout clean for piping, `none` hides the bar
+    #[arg(long, value_enum, default_value_t = ProgressTo::Stderr)]
+    pub progress_to: ProgressTo,
+
+    /// Print none of the per-block lines, only warnings, the progress bar and the final
+    /// report
+    #[arg(long)]
+    pub summary_only: bool,
+
+    /// Log at most this many `🔑 key -> value` lines per root computation, followed by a
+    /// count of the omitted ones
+    #[arg(long, value_name = "N")]
+    pub max_key_logs: Option<usize>,
+
+    /// Before computing each root, check every accumulated key fits in 251 bits and fail
+    /// with a sample of those which do not, rather than silently sharing trie paths
+    #[arg(long)]
+    pub strict_keys: bool,
+
+    /// Also read every Bonsai root before committing the trie and fail unless it equals
+    /// the committed root. Bonsai revisions whose `root_hash` only reflects commits leave
+    /// nothing to compare: a warning says so and the committed root is used as is
+    #[arg(long)]
+    pub check_uncommitted: bool,
+
+    /// Keep one live Bonsai trie over the whole range and insert each block's diffs into
+    /// it, instead of rebuilding the trie at every block. Once the range is done, rebuild
+    /// the trie from the accumulated storage and fail unless the live, rebuilt and
+    /// reference roots all agree. No per-block roots are computed
+    #[arg(long)]
+    pub verify_rebuild_final: bool,
+
+    /// Keep one live Bonsai trie over the whole range, as --verify-rebuild-final does, and
+    /// commit it every N blocks, comparing its root with the reference root at each
+    /// commit. A last commit under a fresh id, compared too, follows the end of the range
+    /// or Ctrl-C, so no applied block is left uncommitted
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub commit_every: Option<u64>,
+
+    /// Fail unless the storage root at --expect-root-block (by default the last block of
+    /// the range) equals this value
+    #[arg(long, value_parser = parse_felt)]
+    pub expect_root: Option<FieldElement>,
+
+    /// Block at which --expect-root applies
+    #[arg(long, requires = "expect_root")]
+    pub expect_root_block: Option<u64>,
+
+    /// Write one self-contained JSON object per computed root to this file as soon as it
+    /// is computed, e.g. to `tail -f` a long run. `match` is null without --reference-check
+    #[arg(long, value_name = "PATH")]
+    pub jsonl_output: Option<PathBuf>,
+
+    /// Write one JSON object per computed root to this file, linking each root to the one
+    /// before it: `block`, `root`, `parent_block`, `parent_root` and `index`, the position
+    /// of the root in the chain. There is no Bonsai commit id to record, every root is
+    /// committed once to a fresh trie under the same first id. A consumer can check every
+    /// `parent_root` is the previous line's `root` to verify the whole sequence of state
+    /// transitions
+    #[arg(long, value_name = "PATH")]
+    pub root_chain: Option<PathBuf>,
+
+    /// Write a `block,keys,hash_seconds` CSV row per computed root to this file, timing
+    /// only Bonsai's commit and root hash, to chart hashing cost against trie size. The
+    /// column is in milliseconds or microseconds instead with --time-unit
+    #[arg(long, value_name = "PATH")]
+    pub root_timing_csv: Option<PathBuf>,
+
+    /// Check every computed root against the root recorded for its block in this golden
+    /// file, as written by --write-golden
+    #[arg(long, value_name = "PATH")]
+    pub golden: Option<PathBuf>,
+
+    /// Record every computed root in this golden file once the range is done
+    #[arg(long, value_name = "PATH")]
+    pub write_golden: Option<PathBuf>,
+
+    /// Comma separated blocks at which to compare the Bonsai and reference roots, e.g.
+    /// `200,250,300`. The range is accumulated once, up to the last checkpoint
+    #[arg(long, value_delimiter = ',', value_name = "BLOCKS")]
+    pub checkpoints: Vec<u64>,
+
+    /// Compare the Bonsai and reference roots at this many blocks drawn at random among
+    /// the blocks of the range which touch the contract, like --checkpoints. The range is
+    /// listed once to find those blocks, then every block up to the last sample is
+    /// accumulated, so the roots at the sampled blocks are the real ones
+    #[arg(long, value_name = "N", conflicts_with = "checkpoints")]
+    pub sample_blocks: Option<usize>,
+
+    /// Seed of --sample-blocks, the same seed drawing the same blocks
+    #[arg(long, default_value_t = 0, requires = "sample_blocks")]
+    pub seed: u64,
+
+    /// Write an attestation of the run to this file once the range is done: its
+    /// parameters, the locked trie and felt dependency versions, a digest of every
+    /// per-block result and the final verdict
+    #[arg(long, value_name = "PATH")]
+    pub attestation: Option<PathBuf>,
+
+    /// Sign the attestation with the ed25519 secret key in this file, 32 bytes of hex
+    #[arg(long, value_name = "PATH", requires = "attestation")]
+    pub attestation_key: Option<PathBuf>,
+
+    /// Write the accumulated storage of the contract to this file once the range is done
+    #[arg(long, value_name = "PATH")]
+    pub export_snapshot: Option<PathBuf>,
+
+    /// Start from the storage saved in this snapshot instead of an empty one. The
+    /// snapshot's root is recomputed and must match the recorded one, and the range
+    /// starts right after the snapshot's block at the earliest
+    #[arg(long, value_name = "PATH")]
+    pub load_snapshot: Option<PathBuf>,
+}
+
+impl Args {
+    /// Parses the command line like [`Parser::parse`]. --contract-from-tx, --from-time
+    /// and --to-time stand in for --contract, --from and --to, and are only rejected
+    /// along with them when both are on the command line: a value the other flag got from
+    /// the environment is overridden instead.
+    pub fn parse_resolved() -> Self {
+        Self::try_parse_resolved(std::env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    fn try_parse_resolved<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let mut command = Self::command();
+        let matches = command.try_get_matches_from_mut(args)?;
+        for (flag, replaced) in [
+            ("contract_from_tx", "contract"),
+            ("from_time", "from"),
+            ("to_time", "to"),
+        ] {
+            let on_command_line = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+            if on_command_line(flag) && on_command_line(replaced) {
+                return Err(command.error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "--{} cannot be used with --{replaced}",
+                        flag.replace('_', "-")
+                    ),
+                ));
+            }
+        }
+
+        Self::from_arg_matches(&matches)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressTo {
+    Stdout,
+    Stderr,
+    None,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Reads a JSON array of `{"key": "0x..", "value": "0x.."}` pairs from stdin and
+    /// compares the Bonsai and reference roots of that exact storage, no network involved.
+    /// Later pairs override earlier ones with the same key
+    CompareStdin {
+        /// Keys are already 251-bit trie paths, given as a string of 251 `0`/`1` bits or
+        /// as a hex value below 2^251, instead of felts to slice
+        #[arg(long)]
+        keys_presliced: bool,
+    },
+
+    /// Computes the Bonsai and reference roots of synthetic storages designed to stress
+    /// edge compression (long shared prefixes, single-bit splits near the root or the
+    /// leaves...), no network involved, and fails unless all agree. Values go through
+    /// --leaf-transform first, to check the engines under other leaf schemes
+    AdversarialCheck,
+
+    /// Writes synthetic diffs for several contracts from one task while others keep
+    /// computing their roots, then checks nothing deadlocked and every final root matches
+    /// the reference trie. No network involved
+    ConcurrencyCheck,
+
+    /// Computes Bonsai roots committing once after all inserts and after every insert,
+    /// over the adversarial patterns and optionally a snapshot's storage, and fails
+    /// unless both commit strategies give the reference root. No network involved
+    CommitCheck {
+        /// Snapshot written by --export-snapshot, to also check real accumulated data
+        #[arg(long)]
+        snapshot: Option<PathBuf>,
+    },
+
+    /// Computes the Bonsai root of a snapshot's storage under several Bonsai storage
+    /// configs, varying how many trie logs and snapshots are kept, and prints the time of
+    /// each and how far its peak resident memory rose above the memory before it. Fails
+    /// unless every config gives the same root. No network involved
+    ConfigSweep {
+        /// Snapshot written by --export-snapshot
+        #[arg(long)]
+        snapshot: PathBuf,
+    },
+
+    /// Times computing a root after every block of a synthetic sequence of blocks, once
+    /// rebuilding a fresh Bonsai trie from the accumulated storage at every block and once
+    /// inserting each block into a live Bonsai trie, and prints the ratio. The reference
+    /// trie, which has no incremental mode, is timed rebuilding. Fails unless every
+    /// strategy gives the same roots. No network involved
+    IncrementalBench {
+        /// Number of blocks
+        #[arg(long, default_value_t = 100)]
+        blocks: usize,
+
+        /// Keys written by each block, some of them overwriting or clearing earlier keys
+        #[arg(long, default_value_t = 50)]
+        keys: usize,
+
+        /// Seed of the synthetic writes
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+
+    /// Computes the roots of a snapshot's storage inserted in ascending, descending and
+    /// shuffled key order, for Bonsai and the reference trie, and fails unless all agree
+    OrderCheck {
+        /// Snapshot written by --export-snapshot
+        #[arg(long)]
+        snapshot: PathBuf,
+
+        /// Seed of the shuffled order
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+
+    /// Runs Bonsai and the reference trie once over a whole storage map and reports both
+    /// roots, the leaves and nodes of the trie and whether the roots match, failing unless
+    /// they do
+    ValidateMap {
+        /// JSON array of `{"key": "0x..", "value": "0x.."}` pairs, later pairs overriding
+        /// earlier ones. Without it, the storage of --contract is accumulated over the range
+        #[arg(long, value_name = "PATH")]
+        map: Option<PathBuf>,
+    },
+
+    /// Lists every contract deployed in the range with its deployment block and class
+    /// hash, e.g. to pick a contract and the --from to start comparing it at
+    ListDeploys {
+        /// Print a JSON array instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Computes the roots of a snapshot's storage with keys sliced at bit 5, as they must
+    /// be, and at --offset, for Bonsai and the reference trie, and reports which agree with
+    /// each other and with the snapshot's root. Only offset 5 is expected to
+    OffsetProbe {
+        /// Snapshot written by --export-snapshot
+        #[arg(long)]
+        snapshot: PathBuf,
+
+        /// Bit of the 256-bit big-endian key at which to start the 251-bit trie path
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=5))]
+        offset: u8,
+    },
+
+    /// Computes the roots of a snapshot's storage with values read big-endian, as they
+    /// must be, and with their bytes reversed as if read little-endian, for Bonsai and the
+    /// reference trie, and reports which agree with the snapshot's root. Only big-endian
+    /// is expected to
+    EndiannessProbe {
+        /// Snapshot written by --export-snapshot
+        #[arg(long)]
+        snapshot: PathBuf,
+    },
+
+    /// Accumulates the Sierra classes declared over the range and compares the Bonsai and
+    /// reference roots of their class trie, hashed with Poseidon. Cairo 0 classes are not
+    /// in the class trie and are only counted. Pass the chain's class trie root with
+    /// --expect-root to check it too
+    ClassTrie,
+
+    /// Loads a snapshot and runs validate-map over its storage, then checks both roots
+    /// against the one recorded in the snapshot. No network involved, the simplest way to
+    /// reproduce a reported mismatch from a shared snapshot
+    RootFromSnapshot {
+        /// Snapshot written by --export-snapshot
+        #[arg(long)]
+        snapshot: PathBuf,
+    },
+
+    /// Compares the results of two runs, each a --jsonl-output or golden file, and prints
+    /// the blocks whose root or reference match differs, or which only one run computed.
+    /// Fails if any block differs. No network involved
+    Diff {
+        /// Results of the first run, e.g. before a dependency bump
+        left: PathBuf,
+
+        /// Results of the second run
+        right: PathBuf,
+    },
+
+    /// Writes every node of a snapshot's trie as JSON, for verifiers recomputing the root
+    /// from the leaves up without trusting either engine. Bonsai keeps its nodes private, so
+    /// the nodes come from the reference trie, next to both roots:
+    ///
+    /// { "bonsai_root": "0x..", "reference_root": "0x..", "nodes": [{ "kind": "edge",
+    ///   "path": "0110..", "hash": "0x..", "edge": "10..", "child": "0x.." }, ..] }
+    ///
+    /// Binary nodes have `left` and `right` hashes instead of `edge` and `child`, leaves a
+    /// `value` and no hash. Children come before their parent and the root last
+    #[cfg(feature = "node-export")]
+    ExportNodes {
+        /// Snapshot written by --export-snapshot
+        #[arg(long)]
+        snapshot: PathBuf,
+        /// File to write the nodes to
+        #[arg(long)]
+        out: PathBuf,
+        /// Give up rather than write a trie of more nodes than this
+        #[arg(long, default_value_t = 1_000_000)]
+        max_nodes: usize,
+    },
+
+    /// Accumulates the contract's class hash, nonce and storage from --from up to and
+    /// including --block, which must be below --to, then prints its contract trie leaf
+    /// `pedersen(pedersen(pedersen(class_hash, storage_root), nonce), 0)`
+    LeafHash {
+        #[arg(long)]
+        block: u64,
+    },
+
+    /// Computes the storage roots of --contract and of --other over the same range, in a
+    /// single pass, and reports the blocks at which their (non-empty) roots coincide
+    Twins {
+        #[arg(long, value_parser = parse_contract_address)]
+        other: FieldElement,
+    },
+
+    /// Accumulates the storage of every --member contract over the range and compares the
+    /// Bonsai and reference roots of their union, as one trie. A key stored by more than
+    /// one member is a collision the union refuses, the members' values being unrelated
+    Union {
+        /// Contract whose storage goes into the union. Repeatable
+        #[arg(long = "member", value_parser = parse_contract_address, required = true)]
+        members: Vec<FieldElement>,
+    },
+
+    /// Runs the full comparison over the range, both engines at every block touching the
+    /// contract, and prints a report: dependency versions, blocks and keys seen, any
+    /// mismatching blocks, the final roots and an overall PASS or FAIL. Fails on FAIL
+    Audit {
+        /// Also write the report as markdown
+        #[arg(long, value_name = "PATH")]
+        markdown: Option<PathBuf>,
+    },
+
+    /// Accumulates the range and, at every block touching the contract, records its root
+    /// under both engines, the number of keys the block wrote and whether the roots match:
+    /// the history of the contract's storage root. Fails if the engines ever disagree
+    Timeline {
+        /// Write the timeline as `block,keys_changed,bonsai_root,reference_root,match` CSV
+        #[arg(long, value_name = "PATH")]
+        csv: Option<PathBuf>,
+
+        /// Write the timeline as a JSON array
+        #[arg(long, value_name = "PATH")]
+        json: Option<PathBuf>,
+    },
+
+    /// Accumulates from --from once, and reports whether the contract's storage root
+    /// changed between --from-block and --to-block, along with both roots
+    RootDelta {
+        #[arg(long)]
+        from_block: u64,
+
+        #[arg(long)]
+        to_block: u64,
+    },
+
+    /// Accumulates the range until the block deploying the contract, then compares the
+    /// Bonsai and reference roots of its initial storage at that exact block. The default
+    /// contract is deployed within the default range
+    DeployCheck,
+
+    /// Fetches the contract's diffs of the range once and, if the Bonsai and reference
+    /// roots disagree at its end, binary searches the first block after which they do.
+    /// Each probe rebuilds both tries from the diffs up to a block, so only about
+    /// log2(blocks with diffs) rebuilds are needed. Divergences which come and go may be
+    /// pinned to any block where the roots start disagreeing, not necessarily the first
+    Bisect,
+
+    /// Accumulates the range, reverts the accumulated storage to --revert-to by undoing
+    /// the later blocks' writes, then applies alternate blocks read from a file. A live
+    /// Bonsai trie commits every block alongside and is reverted to the commit of
+    /// --revert-to with Bonsai's own `revert_to`, which needs Bonsai to keep the trie logs of
+    /// the reverted blocks. The reverted storage must equal the one rebuilt from scratch up
+    /// to --revert-to, and the live, rebuilt and reference roots must agree at the revert
+    /// and at every block of the reorged chain
+    Reorg {
+        /// Last block kept from the canonical chain
+        #[arg(long)]
+        revert_to: u64,
+
+        /// JSON object mapping each alternate block number, all after --revert-to, to its
+        /// `[{"key": "0x..", "value": "0x.."}]` storage diffs for the contract
+        #[arg(long, value_name = "PATH")]
+        alternate: PathBuf,
+    },
+
+    /// Applies the contract's diffs of the range in forward and in reverse block order and
+    /// checks both yield the same root. Only expected to hold when no key is written in
+    /// more than one block, a difference is merely reported otherwise
+    ReverseCheck,
+
+    /// Accumulates the range up to its last block, then applies that block alone and
+    /// prints the root before and after it, along with every key it changed
+    LatestDelta,
+
+    /// Accumulates the diffs from --from up to and including --block, which must be below
+    /// --to, then prints the roots of the storage when zero writes delete their key and
+    /// when they store a zero leaf, for Bonsai and the reference trie. Pass the chain's
+    /// root with --expect-root to see which reading matches it. Fails if the storage has
+    /// no zero value and the roots still differ
+    ZeroSemantics {
+        #[arg(long)]
+        block: u64,
+    },
+
+    /// Accumulates the diffs from --from up to and including --block, which must be below
+    /// --to, then fetches the absolute value of every accumulated key at --block with
+    /// `starknet_getStorageAt` and compares the root of those values to the accumulated
+    /// root
+    StorageAt {
+        #[arg(long)]
+        block: u64,
+
+        /// JSON-RPC endpoint of a full node serving `starknet_getStorageAt`
+        #[arg(long)]
+        rpc_url: Url,
+
+        /// Fetch the keys listed in this file, one hex key per line, instead of the
+        /// accumulated ones
+        #[arg(long, value_name = "PATH")]
+        keys_file: Option<PathBuf>,
+    },
+
+    /// Fetches the state update of every block in the range from both the gateway and a
+    /// JSON-RPC node, accumulates the contract's diffs from each source separately and
+    /// compares the two roots. Reports every block whose diffs differ between the sources,
+    /// which points at the data rather than at the trie
+    CrossProvider {
+        /// JSON-RPC endpoint of a full node serving `starknet_getStateUpdate`
+        #[arg(long)]
+        rpc_url: Url,
+    },
+}
+
+fn parse_key_prefix(s: &str) -> Result<String, String> {
+    let prefix = s.trim_start_matches("0x").to_ascii_lowercase();
+
+    if prefix.len() > 64 {
+        return Err(format!(
+            "key prefix is {} hex digits long, keys only have 64",
+            prefix.len()
+        ));
+    }
+    if !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("key prefix {s} is not valid hex"));
+    }
+    // Lowercase hex digits sort like their values, so comparing with the largest key's
+    // digits tells whether any key starts with the prefix
+    let max_key = format!("07{}", "f".repeat(62));
+    if prefix.as_str() > &max_key[..prefix.len()] {
+        return Err(format!(
+            "key prefix {s} matches no key, storage keys are below 2^251 so their digits \
+            start with 0 then a digit up to 7"
+        ));
+    }
+
+    Ok(prefix)
+}
+
+fn parse_rps(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rps) if rps > 0.0 && rps.is_finite() => Ok(rps),
+        _ => Err(format!(
+            "{s} is not a positive number of requests per second"
+        )),
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("{s} does not start with a number"))?;
+
+    let seconds = match unit {
+        "s" | "" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(format!("unknown unit {unit} in {s}, expected s, m or h")),
+    };
+
+    value
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("{s} is too long a duration"))
+}
+
+fn parse_contract_address(s: &str) -> Result<FieldElement, String> {
+    FieldElement::from_hex_be(s).map_err(|_| format!("invalid contract address: {s}"))
+}
+
+fn parse_felt(s: &str) -> Result<FieldElement, String> {
+    FieldElement::from_hex_be(s).map_err(|e| format!("invalid felt {s}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_felts_are_rejected() {
+        let modulus = "0x800000000000011000000000000000000000000000000000000000000000001";
+        let too_long = format!("0x1{}", "0".repeat(64));
+
+        for s in ["0xzz", "hello", "0x1g", modulus, too_long.as_str()] {
+            assert!(parse_contract_address(s).is_err(), "{s}");
+            assert!(parse_felt(s).is_err(), "{s}");
+        }
+        assert_eq!(parse_felt("0x1a").unwrap(), FieldElement::from(0x1au64));
+        assert_eq!(
+            parse_contract_address("0x1a").unwrap(),
+            FieldElement::from(0x1au64)
+        );
+    }
+
+    /// The only test touching the environment, so that no other test parses arguments
+    /// while it is set
+    #[test]
+    fn command_line_flags_override_the_environment() {
+        std::env::set_var("DEOXYS_FROM", "5");
+        std::env::set_var("DEOXYS_NETWORK", "sepolia");
+
+        let args = Args::try_parse_resolved(["deoxys-test"]).unwrap();
+        assert_eq!((args.from, args.network), (5, Network::Sepolia));
+        let args = Args::try_parse_resolved(["deoxys-test", "--from", "7", "--network", "mainnet"])
+            .unwrap();
+        assert_eq!((args.from, args.network), (7, Network::Mainnet));
+
+        // --from-time replaces the environment's --from, but not one on the command line
+        let args = Args::try_parse_resolved(["deoxys-test", "--from-time", "1700000000"]).unwrap();
+        assert_eq!(args.from_time, Some(1_700_000_000));
+        let conflict =
+            Args::try_parse_resolved(["deoxys-test", "--from", "7", "--from-time", "1700000000"]);
+        assert_eq!(conflict.unwrap_err().kind(), ErrorKind::ArgumentConflict);
+
+        std::env::remove_var("DEOXYS_FROM");
+        std::env::remove_var("DEOXYS_NETWORK");
+    }
+
+    #[test]
+    fn durations_which_overflow_are_rejected() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+
+        let max = u64::MAX;
+        assert_eq!(
+            parse_duration(&format!("{max}s")).unwrap(),
+            Duration::from_secs(max)
+        );
+        for unit in ["m", "h"] {
+            assert!(parse_duration(&format!("{max}{unit}")).is_err(), "{unit}");
+        }
+        assert!(parse_duration("2d").is_err());
+    }
+
+    #[test]
+    fn unreachable_key_prefixes_are_rejected() {
+        let max_key = format!("0x07{}", "f".repeat(62));
+        for s in ["0x01", "0x07", "0x", "0x0", max_key.as_str()] {
+            assert!(parse_key_prefix(s).is_ok(), "{s}");
+        }
+        let above_max = format!("0x08{}", "0".repeat(62));
+        for s in ["0x0a", "0x08", "0x1", "0xf", above_max.as_str()] {
+            assert!(parse_key_prefix(s).is_err(), "{s}");
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Golden files: known-good storage roots per block, to detect drift across dependency
+//! changes.
+//!
+//! The file is a JSON object mapping block numbers to roots, e.g. `{"200": "0x1a.."}`.
+//! Only blocks which touched the contract, and thus had their root computed, are listed.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::Context;
+use starknet::core::types::FieldElement;
+
+pub type Roots = BTreeMap<u64, FieldElement>;
+
+pub fn read(path: &Path) -> anyhow::Result<Roots> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open golden file {}", path.display()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to parse golden file {}", path.display()))
+}
+
+pub fn write(path: &Path, roots: &Roots) -> anyhow::Result<()> {
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create golden file {}", path.display()))?;
+    serde_json::to_writer_pretty(BufWriter::new(file), roots)
+        .with_context(|| format!("Failed to write golden file {}", path.display()))
+}
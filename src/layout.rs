@@ -0,0 +1,72 @@
+//! Human readable view of storage values packing several fields into one felt.
+//!
+//! Purely diagnostic: roots are always computed over the raw values. A layout file maps
+//! storage keys to the fields packed in their value, bit offsets counting from the least
+//! significant bit:
+//!
+//! ```json
+//! { "0x0a1b..": [{ "name": "balance", "offset": 0, "bits": 128 },
+//!                { "name": "timestamp", "offset": 128, "bits": 64 }] }
+//! ```
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use bitvec::prelude::Msb0;
+use bitvec::view::BitView;
+use serde::Deserialize;
+use starknet::core::types::FieldElement;
+
+#[derive(Deserialize)]
+pub struct Field {
+    pub name: String,
+    pub offset: usize,
+    pub bits: usize,
+}
+
+pub type Layout = HashMap<FieldElement, Vec<Field>>;
+
+pub fn read(path: &Path) -> anyhow::Result<Layout> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open layout file {}", path.display()))?;
+    let layout: Layout = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to parse layout file {}", path.display()))?;
+
+    for field in layout.values().flatten() {
+        if field.bits == 0 || field.offset + field.bits > 251 {
+            return Err(anyhow!(
+                "Field {} of {} spans bits {}..{}, outside of a felt",
+                field.name,
+                path.display(),
+                field.offset,
+                field.offset + field.bits
+            ));
+        }
+    }
+
+    Ok(layout)
+}
+
+/// Values of `fields` packed in `value`.
+pub fn unpack<'a>(value: &FieldElement, fields: &'a [Field]) -> Vec<(&'a str, FieldElement)> {
+    let bytes = value.to_bytes_be();
+    let bits = bytes.view_bits::<Msb0>();
+
+    fields
+        .iter()
+        .map(|field| {
+            let end = 256 - field.offset;
+            let mut unpacked = [0u8; 32];
+            unpacked.view_bits_mut::<Msb0>()[256 - field.bits..]
+                .copy_from_bitslice(&bits[end - field.bits..end]);
+
+            (
+                field.name.as_str(),
+                FieldElement::from_bytes_be(&unpacked).unwrap(),
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,2559 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Once};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use bitvec::prelude::{BitVec, Msb0};
+use bitvec::view::BitView;
+use bonsai_trie::databases::{create_rocks_db, RocksDBConfig};
+use bonsai_trie::id::BasicIdBuilder;
+use bonsai_trie::BonsaiStorageConfig;
+use bonsai_trie::{databases::RocksDB, BonsaiStorage};
+use indicatif::ProgressBar;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use starknet::core::chain_id;
+use starknet::core::types::FieldElement;
+use starknet::providers::{
+    sequencer::models::{
+        state_update::{StateDiff, StorageDiff},
+        BlockId::{self},
+        StateUpdate, TransactionType,
+    },
+    ProviderError, SequencerGatewayProvider,
+};
+use starknet_types_core::felt::Felt;
+use starknet_types_core::hash::{Pedersen, Poseidon, StarkHash};
+use tempfile::tempdir;
+use tokio::sync::mpsc;
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use url::Url;
+
+use crate::layout::Layout;
+use crate::metrics::Metrics;
+use crate::roots::{BonsaiRoot, ReferenceRoot, RootContext};
+
+pub mod adversarial;
+pub mod attestation;
+pub mod audit;
+pub mod golden;
+pub mod layout;
+pub mod metrics;
+pub mod reference;
+pub mod results;
+pub mod roots;
+pub mod rpc;
+pub mod session;
+pub mod snapshot;
+
+lazy_static! {
+    pub static ref CONTRACT_STORAGE: RwLock<HashMap<FieldElement, RwLock<HashMap<FieldElement, FieldElement>>>> =
+        RwLock::new(HashMap::new());
+    pub static ref CONTRACT_DATA: RwLock<HashMap<FieldElement, ContractData>> =
+        RwLock::new(HashMap::new());
+    /// Global state root after each block fetched by number, to chain the next ones to it
+    static ref STATE_ROOTS: RwLock<HashMap<u64, FieldElement>> = RwLock::new(HashMap::new());
+}
+
+/// Class hash and nonce of a contract, accumulated like its storage.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ContractData {
+    pub class_hash: FieldElement,
+    pub nonce: FieldElement,
+}
+
+pub const IDENTIFIER: &[u8; 10] = b"0xcontract";
+
+/// Storage tries have a fixed height of 251 bits
+pub const TRIE_KEY_LEN: usize = 251;
+
+/// Storage root computed for a block which touched the watched contract.
+pub struct BlockResult {
+    pub block: u64,
+    pub contract: FieldElement,
+    pub root: BonsaiRoot,
+    /// Root before this block's diff was applied
+    pub previous_root: Felt,
+    /// Number of keys inserted into the trie to compute `root`
+    pub keys: usize,
+    /// Number of storage diffs of this block
+    pub written: usize,
+    /// Number of keys whose nonzero value this block set to zero
+    pub cleared: usize,
+    /// Time Bonsai spent hashing the trie of `keys` leaves, excluding insertions
+    pub hash_time: Duration,
+    /// Whether the reference trie disagreed, only possible with `no_assert`
+    pub mismatch: bool,
+    /// Root of the reference trie, with `reference_check`
+    pub reference_root: Option<ReferenceRoot>,
+}
+
+/// Counters maintained over a whole [`process_range`] run.
+#[derive(Default, Debug)]
+pub struct RunStats {
+    pub blocks: u64,
+    /// Blocks which touched the contract and had their root computed
+    pub blocks_with_diffs: u64,
+    /// Blocks whose state diff touches no contract's storage at all
+    pub empty_blocks: u64,
+    pub keys_inserted: u64,
+    /// Nonzero values set to zero over the run
+    pub keys_cleared: u64,
+    /// Blocks whose Bonsai and reference roots differed
+    pub mismatches: u64,
+    /// First block left unprocessed because the deadline was reached
+    pub stopped_at: Option<u64>,
+    /// Last block whose state update was applied, none if the deadline passed before the
+    /// first one
+    pub last_applied: Option<u64>,
+}
+
+/// Restricts which accumulated storage keys are inserted into the trie.
+#[derive(Default)]
+pub struct KeyFilter {
+    /// Hex prefix (no `0x`, lowercase) matched against the 64-digit big-endian key
+    pub prefix: Option<String>,
+    /// Keys left out of every trie as if never written, whatever their value. Unlike the
+    /// prefix, which selects a subtree, this removes individual slots which change all
+    /// the time (timestamps, counters) to compare only the stable part of the storage
+    pub volatile: HashSet<FieldElement>,
+}
+
+impl KeyFilter {
+    pub fn matches(&self, key: &FieldElement) -> bool {
+        if self.volatile.contains(key) {
+            return false;
+        }
+
+        match &self.prefix {
+            Some(prefix) => {
+                let hex: String = key
+                    .to_bytes_be()
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect();
+                hex.starts_with(prefix.as_str())
+            }
+            None => true,
+        }
+    }
+}
+
+/// Applied to every storage value before it becomes a leaf, identically for each trie.
+///
+/// Every Starknet version so far, up to 0.13, stores the value itself as the leaf, which is
+/// [`LeafTransform::Identity`]. The other schemes are hypothetical, to check both engines
+/// would still agree if a future version hashed storage leaves.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LeafTransform {
+    /// Insert the storage value as is, which is what Starknet does
+    #[default]
+    Identity,
+    /// Insert `pedersen(value, 0)` instead of the value
+    Pedersen,
+    /// Insert `poseidon(value, 0)` instead of the value
+    Poseidon,
+}
+
+impl LeafTransform {
+    /// The leaf for `value`. Zero stays zero whatever the scheme, so that writing zero
+    /// still deletes the key.
+    pub fn apply(self, value: FieldElement) -> FieldElement {
+        if value == FieldElement::ZERO {
+            return value;
+        }
+        match self {
+            LeafTransform::Identity => value,
+            LeafTransform::Pedersen => {
+                to_field_element(&Pedersen::hash(&to_felt(&value), &Felt::ZERO))
+            }
+            LeafTransform::Poseidon => {
+                to_field_element(&Poseidon::hash(&to_felt(&value), &Felt::ZERO))
+            }
+        }
+    }
+}
+
+/// How roots are rendered wherever they are printed.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RootFormat {
+    /// `0x`-prefixed hex, zero-padded to 64 characters
+    #[default]
+    Padded,
+    /// `0x`-prefixed hex without leading zeros
+    Minimal,
+    /// Base 10
+    Decimal,
+}
+
+impl RootFormat {
+    pub fn format(self, root: &Felt) -> String {
+        match self {
+            RootFormat::Padded => format!("{root:#064x}"),
+            RootFormat::Minimal => format!("{root:#x}"),
+            RootFormat::Decimal => root.to_string(),
+        }
+    }
+}
+
+/// Unit durations are printed in.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeUnit {
+    /// Whichever unit reads best for each duration, seconds in machine-readable output
+    #[default]
+    Auto,
+    S,
+    Ms,
+    Us,
+}
+
+impl TimeUnit {
+    pub fn format(self, duration: Duration, precision: usize) -> String {
+        match self {
+            TimeUnit::Auto => format!("{duration:.precision$?}"),
+            unit => format!("{:.precision$}{}", unit.value(duration), unit.suffix()),
+        }
+    }
+
+    /// `duration` as a bare number of this unit.
+    pub fn value(self, duration: Duration) -> f64 {
+        match self {
+            TimeUnit::Auto | TimeUnit::S => duration.as_secs_f64(),
+            TimeUnit::Ms => duration.as_secs_f64() * 1e3,
+            TimeUnit::Us => duration.as_secs_f64() * 1e6,
+        }
+    }
+
+    pub fn suffix(self) -> &'static str {
+        match self {
+            TimeUnit::Auto | TimeUnit::S => "s",
+            TimeUnit::Ms => "ms",
+            TimeUnit::Us => "µs",
+        }
+    }
+}
+
+pub const MAINNET_GATEWAY: &str = "https://alpha-mainnet.starknet.io/gateway";
+pub const MAINNET_FEEDER_GATEWAY: &str = "https://alpha-mainnet.starknet.io/feeder_gateway";
+pub const SEPOLIA_GATEWAY: &str = "https://alpha-sepolia.starknet.io/gateway";
+pub const SEPOLIA_FEEDER_GATEWAY: &str = "https://alpha-sepolia.starknet.io/feeder_gateway";
+
+/// Starknet network whose sequencer gateway is queried.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Sepolia,
+}
+
+impl Network {
+    pub fn gateway(self) -> &'static str {
+        match self {
+            Network::Mainnet => MAINNET_GATEWAY,
+            Network::Sepolia => SEPOLIA_GATEWAY,
+        }
+    }
+
+    pub fn feeder_gateway(self) -> &'static str {
+        match self {
+            Network::Mainnet => MAINNET_FEEDER_GATEWAY,
+            Network::Sepolia => SEPOLIA_FEEDER_GATEWAY,
+        }
+    }
+
+    pub fn chain_id(self) -> FieldElement {
+        match self {
+            Network::Mainnet => chain_id::MAINNET,
+            Network::Sepolia => {
+                FieldElement::from_byte_slice_be(b"SN_SEPOLIA").expect("fits in a felt")
+            }
+        }
+    }
+}
+
+/// HTTP client whose requests give up after `request_timeout`, shared by the provider and
+/// the raw re-fetches of [`FetchConfig::save_raw_on_error`].
+///
+/// The timeout applies to each attempt of [`get_state_update`], which retries up to 15
+/// times, 5s apart: a gateway which never answers is given up on after about
+/// `15 * (request_timeout + 5s)`.
+pub fn http_client(request_timeout: Duration) -> anyhow::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(request_timeout)
+        .build()
+        .context("Failed to build the HTTP client")
+}
+
+/// Sequencer provider of `network` sending its requests through `client`.
+pub fn network_provider(
+    network: Network,
+    client: reqwest::Client,
+) -> anyhow::Result<SequencerGatewayProvider> {
+    Ok(SequencerGatewayProvider::new_with_client(
+        Url::parse(network.gateway())?,
+        Url::parse(network.feeder_gateway())?,
+        network.chain_id(),
+        client,
+    ))
+}
+
+/// How state updates are fetched from the gateway.
+pub struct FetchConfig {
+    /// Feeder gateway the provider talks to, used to re-fetch raw responses
+    pub feeder_gateway_url: String,
+    /// Client raw responses are re-fetched with, see [`http_client`]
+    pub client: reqwest::Client,
+    /// Save the raw response of state updates which fail to fetch in this directory
+    pub save_raw_on_error: Option<PathBuf>,
+    /// Caps the rate of gateway requests
+    pub rate_limiter: Option<RateLimiter>,
+    /// Bar fetch warnings are printed above, see [`print_result`]
+    pub bar: ProgressBar,
+    /// Wait between two attempts at fetching a state update, doubled on rate limiting
+    pub retry_delay: Duration,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            feeder_gateway_url: MAINNET_FEEDER_GATEWAY.to_string(),
+            client: reqwest::Client::new(),
+            save_raw_on_error: None,
+            rate_limiter: None,
+            bar: ProgressBar::hidden(),
+            retry_delay: RETRY_DELAY,
+        }
+    }
+}
+
+/// Token bucket holding a single token, refilled `rps` times per second: requests are
+/// spaced evenly however many tasks share the limiter.
+pub struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rps: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / rps),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until a request may be sent.
+    pub async fn wait(&self) {
+        let mut next = self.next.lock().await;
+        tokio::time::sleep_until(*next).await;
+        *next = Instant::now().max(*next) + self.interval;
+    }
+}
+
+/// Knobs for a comparison run, shared by every block of the range.
+#[derive(Default)]
+pub struct RunConfig {
+    pub fetch: FetchConfig,
+    pub filter: KeyFilter,
+    pub leaf_transform: LeafTransform,
+    pub root_format: RootFormat,
+    /// Also compute each root with [`reference::root`] and assert it matches Bonsai
+    pub reference_check: bool,
+    /// Warn when a block changes more keys than this for the watched contract
+    pub max_keys_per_block: Option<usize>,
+    /// Fail instead of warning when `max_keys_per_block` is exceeded
+    pub max_keys_error: bool,
+    /// Skip the root computation of blocks which leave the accumulated storage unchanged
+    pub skip_noop: bool,
+    /// Log whether each block changed the root, with the roots before and after it, for
+    /// Bonsai and the reference trie, and fail if they disagree on whether it changed
+    pub root_delta: bool,
+    pub bonsai: BonsaiOptions,
+    /// Only keep warnings of the per-block output
+    pub summary_only: bool,
+    /// Log every key whose nonzero value is set to zero
+    pub log_clears: bool,
+    /// Counters updated after every block of [`process_range`]
+    pub metrics: Option<Arc<Metrics>>,
+    /// Log the fields packed in the values of these keys when they change
+    pub layout: Option<Layout>,
+    /// Only accumulate storage diffs, leaving `CONTRACT_DATA` empty
+    pub storage_only: bool,
+    /// Log reference check mismatches and go on instead of failing the block
+    pub no_assert: bool,
+    /// [`process_range`] stops before the first block it reaches past this instant
+    pub deadline: Option<std::time::Instant>,
+    /// Check every key fits in 251 bits before computing a root, see [`check_keys_fit`]
+    pub strict_keys: bool,
+    /// Insert keys in ascending order rather than in the accumulated map's random order
+    pub deterministic: bool,
+}
+
+/// How [`bonsai_root`] builds its trie.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BonsaiOptions {
+    /// Log at most this many keys per [`storage_root`] computation
+    pub max_key_logs: Option<usize>,
+    /// Also read the root before committing and fail unless it equals the committed one.
+    /// If Bonsai only reports committed roots, warns and uses the committed root alone
+    pub check_uncommitted: bool,
+    /// Commit after every insert instead of once after all of them
+    pub commit_each_insert: bool,
+    /// Overrides of Bonsai's own storage config, its defaults where `None`
+    pub storage: StorageTuning,
+}
+
+/// Fields of Bonsai's `BonsaiStorageConfig`, which only affect how the trie's history is
+/// kept, never its root.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StorageTuning {
+    pub max_saved_trie_logs: Option<Option<usize>>,
+    pub max_saved_snapshots: Option<Option<usize>>,
+    pub snapshot_interval: Option<u64>,
+}
+
+impl StorageTuning {
+    fn config(self) -> BonsaiStorageConfig {
+        let default = BonsaiStorageConfig::default();
+        BonsaiStorageConfig {
+            max_saved_trie_logs: self
+                .max_saved_trie_logs
+                .unwrap_or(default.max_saved_trie_logs),
+            max_saved_snapshots: self
+                .max_saved_snapshots
+                .unwrap_or(default.max_saved_snapshots),
+            snapshot_interval: self.snapshot_interval.unwrap_or(default.snapshot_interval),
+        }
+    }
+}
+
+/// Called after each [`BlockResult`]. Runs on the driver thread, so keep it short.
+pub type BlockHook<'a> = &'a mut (dyn FnMut(&BlockResult) + Send);
+
+pub async fn process_range(
+    provider: &impl StateUpdateSource,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+    mut on_block: Option<BlockHook<'_>>,
+) -> anyhow::Result<RunStats> {
+    let mut stats = RunStats::default();
+    let mut root = current_root(contract_address, config).await?;
+
+    for i in block_range {
+        let result = process_block(
+            provider,
+            contract_address,
+            i,
+            bar,
+            config,
+            &mut root,
+            &mut stats,
+        )
+        .await?;
+        if stats.stopped_at.is_some() {
+            break;
+        }
+
+        if let (Some(result), Some(on_block)) = (result, on_block.as_mut()) {
+            on_block(&result);
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Same pipeline as [`process_range`], but yields each [`BlockResult`] as soon as it is
+/// computed. The range is driven on a spawned task which runs at most one block ahead of
+/// the consumer: it computes the next result while the previous one waits to be taken,
+/// then waits. It stops after yielding the first error or at the deadline. Dropping the
+/// stream cancels the task once the block in flight is done; storage accumulated up to
+/// that point, including the block computed ahead, stays in `CONTRACT_STORAGE`.
+pub fn process_range_stream<P: StateUpdateSource + Send + Sync + 'static>(
+    provider: P,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    bar: ProgressBar,
+    config: RunConfig,
+) -> impl Stream<Item = anyhow::Result<BlockResult>> {
+    let (tx, rx) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        let mut root = match current_root(contract_address, &config).await {
+            Ok(root) => root,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+
+        let mut stats = RunStats::default();
+        for i in block_range {
+            let result = process_block(
+                &provider,
+                contract_address,
+                i,
+                &bar,
+                &config,
+                &mut root,
+                &mut stats,
+            )
+            .await;
+            if stats.stopped_at.is_some() {
+                break;
+            }
+
+            let result = match result {
+                Ok(Some(result)) => Ok(result),
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            let failed = result.is_err();
+
+            if tx.send(result).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+/// Fetches and applies block `i`, counting it in `stats` and the metrics. Once the
+/// deadline has passed, only records `i` as `stats.stopped_at`.
+async fn process_block(
+    provider: &impl StateUpdateSource,
+    contract_address: FieldElement,
+    i: u64,
+    bar: &ProgressBar,
+    config: &RunConfig,
+    root: &mut Felt,
+    stats: &mut RunStats,
+) -> anyhow::Result<Option<BlockResult>> {
+    let start = std::time::Instant::now();
+    if config.deadline.is_some_and(|deadline| start >= deadline) {
+        stats.stopped_at = Some(i);
+        return Ok(None);
+    }
+    bar.inc(1);
+
+    let state_update = get_state_update(provider, BlockId::Number(i), &config.fetch).await?;
+    if state_update.state_diff.storage_diffs.is_empty() {
+        stats.empty_blocks += 1;
+    }
+    let result = apply_state_update(&state_update, contract_address, i, bar, config, root).await?;
+    stats.blocks += 1;
+    stats.last_applied = Some(i);
+
+    if let Some(metrics) = &config.metrics {
+        metrics.record(i, start.elapsed(), result.as_ref());
+    }
+    if let Some(result) = &result {
+        stats.blocks_with_diffs += 1;
+        stats.keys_inserted += result.keys as u64;
+        stats.keys_cleared += result.cleared as u64;
+        stats.mismatches += result.mismatch as u64;
+    }
+
+    Ok(result)
+}
+
+/// Accumulates the changes of block `i` to `contract_address` and computes its new root,
+/// if the block touched its storage. `root` is the root before the block and is updated.
+pub async fn apply_state_update(
+    state_update: &StateUpdate,
+    contract_address: FieldElement,
+    i: u64,
+    bar: &ProgressBar,
+    config: &RunConfig,
+    root: &mut Felt,
+) -> anyhow::Result<Option<BlockResult>> {
+    if !config.storage_only {
+        save_contract_data(contract_address, &state_update.state_diff).await;
+    }
+
+    // Common on quiet stretches of the chain: nothing to accumulate, the root is unchanged
+    if state_update.state_diff.storage_diffs.is_empty() {
+        return Ok(None);
+    }
+    let Some(storage_updates) = state_update.state_diff.storage_diffs.get(&contract_address) else {
+        return Ok(None);
+    };
+
+    // Per-block lines go nowhere with --summary-only, warnings are still printed
+    let log = |line: String| {
+        if !config.summary_only {
+            print_result(bar, line);
+        }
+    };
+
+    log(format!("🧱 block {i}"));
+
+    if let Some(max_keys) = config.max_keys_per_block {
+        if storage_updates.len() > max_keys {
+            let msg = format!(
+                "block {i} changes {} keys, above the limit of {max_keys}",
+                storage_updates.len()
+            );
+
+            if config.max_keys_error {
+                return Err(anyhow!(msg));
+            }
+            print_result(bar, format!("⚠️ {msg}"));
+        }
+    }
+
+    // The reference trie keeps no root between blocks, take it before the block lands
+    let reference_before = if config.root_delta {
+        Some(reference_root(contract_address, config).await)
+    } else {
+        None
+    };
+    let change = save_storage_update(contract_address, storage_updates).await;
+    if let Some(layout) = &config.layout {
+        for StorageDiff { key, value } in storage_updates {
+            if let Some(fields) = layout.get(key) {
+                let fields: Vec<_> = layout::unpack(value, fields)
+                    .into_iter()
+                    .map(|(name, value)| format!("{name}={value:#x}"))
+                    .collect();
+                log(format!("📦 {key:#x}: {}", fields.join(" ")));
+            }
+        }
+    }
+    if config.log_clears {
+        for key in &change.cleared {
+            log(format!("🧹 block {i} cleared {key:#x}"));
+        }
+    }
+    if config.skip_noop && !change.changed {
+        log(format!("💤 no-op block {i}, storage unchanged"));
+        return Ok(None);
+    }
+
+    let (storage_root, keys, zeros, hash_time) =
+        storage_root_timed(contract_address, bar, !config.summary_only, config).await?;
+    log(format!(
+        "🌳 storage root: {}",
+        config.root_format.format(&storage_root)
+    ));
+
+    // An empty trie has a zero root, anything else means leaves survived their deletion
+    if storage_root != Felt::ZERO && zeros == keys {
+        print_result(
+            bar,
+            format!(
+                "⚠️ block {i}: root {} is not the empty root although all {keys} keys are zero",
+                config.root_format.format(&storage_root)
+            ),
+        );
+    }
+
+    let context = RootContext {
+        contract: contract_address,
+        block: i,
+    };
+    let bonsai = BonsaiRoot {
+        context,
+        root: storage_root,
+    };
+    let mut mismatch = false;
+    let mut checked_root = None;
+    if config.reference_check {
+        let reference = ReferenceRoot {
+            context,
+            root: reference_root(contract_address, config).await,
+        };
+        if !bonsai.matches(&reference)? {
+            let msg = format!(
+                "Bonsai and reference roots differ at block {i}: {} != {}",
+                config.root_format.format(&bonsai.root),
+                config.root_format.format(&reference.root)
+            );
+            if !config.no_assert {
+                return Err(anyhow!(msg));
+            }
+
+            print_result(bar, format!("❌ MISMATCH at block {i}: {msg}"));
+            mismatch = true;
+        }
+        checked_root = Some(reference);
+    }
+
+    let previous_root = std::mem::replace(root, storage_root);
+    if let Some(reference_before) = reference_before {
+        let reference_after = match &checked_root {
+            Some(reference) => reference.root,
+            None => reference_root(contract_address, config).await,
+        };
+        let changed = previous_root != storage_root;
+        let reference_changed = reference_before != reference_after;
+        log(format!(
+            "🔁 changed: {changed} ({} -> {}), reference changed: {reference_changed} ({} -> {})",
+            config.root_format.format(&previous_root),
+            config.root_format.format(&storage_root),
+            config.root_format.format(&reference_before),
+            config.root_format.format(&reference_after)
+        ));
+
+        if changed != reference_changed {
+            let msg = format!(
+                "Bonsai and reference disagree on whether block {i} changed the root: \
+                {changed} != {reference_changed}"
+            );
+            if !config.no_assert {
+                return Err(anyhow!(msg));
+            }
+
+            print_result(bar, format!("❌ MISMATCH at block {i}: {msg}"));
+            mismatch = true;
+        }
+    }
+
+    Ok(Some(BlockResult {
+        block: i,
+        contract: contract_address,
+        root: bonsai,
+        previous_root,
+        keys,
+        written: storage_updates.len(),
+        cleared: change.cleared.len(),
+        hash_time,
+        mismatch,
+        reference_root: checked_root,
+    }))
+}
+
+/// First block whose timestamp is at or after `timestamp` (unix seconds), or the block
+/// after the latest one if there is none yet.
+///
+/// Binary searches block headers between genesis and the latest block, so it costs about
+/// log2(latest) header fetches. Block timestamps are only assumed non-decreasing, which
+/// the sequencer enforces; the result is exact under that assumption, blocks sharing a
+/// timestamp all fall on the same side.
+pub async fn block_at_timestamp(
+    provider: &SequencerGatewayProvider,
+    timestamp: u64,
+) -> anyhow::Result<u64> {
+    let latest = provider
+        .get_block(BlockId::Latest)
+        .await
+        .context("Failed to retrieve the latest block")?;
+    let latest_number = latest
+        .block_number
+        .ok_or_else(|| anyhow!("Latest block has no number"))?;
+    if latest.timestamp < timestamp {
+        return Ok(latest_number + 1);
+    }
+
+    // Invariant: the answer lies in low..=high
+    let (mut low, mut high) = (0, latest_number);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let block = provider
+            .get_block(BlockId::Number(middle))
+            .await
+            .with_context(|| format!("Failed to retrieve block {middle}"))?;
+
+        if block.timestamp < timestamp {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    Ok(low)
+}
+
+/// Storage of `contract_address` after applying the diffs of `block_range` in order to an
+/// empty storage, along with the last block applied. Only fetches and merges: the global
+/// `CONTRACT_STORAGE` is left untouched and no trie is built.
+pub async fn accumulate_storage(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    fetch: &FetchConfig,
+) -> anyhow::Result<(HashMap<FieldElement, FieldElement>, Option<u64>)> {
+    let mut storage = HashMap::new();
+    let mut last_block = None;
+
+    for i in block_range {
+        bar.inc(1);
+
+        let state_update = get_state_update(provider, BlockId::Number(i), fetch).await?;
+        if let Some(storage_diffs) = state_update.state_diff.storage_diffs.get(&contract_address) {
+            storage.extend(storage_diffs.iter().map(|diff| (diff.key, diff.value)));
+        }
+        last_block = Some(i);
+    }
+
+    Ok((storage, last_block))
+}
+
+/// Compiled class hash of every Sierra class declared in `block_range`, by class hash,
+/// along with the number of Cairo 0 classes declared, which are not in the class trie.
+pub async fn declared_classes(
+    provider: &SequencerGatewayProvider,
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    fetch: &FetchConfig,
+) -> anyhow::Result<(HashMap<FieldElement, FieldElement>, usize)> {
+    let mut classes = HashMap::new();
+    let mut legacy = 0;
+
+    for i in block_range {
+        bar.inc(1);
+
+        let state_update = get_state_update(provider, BlockId::Number(i), fetch).await?;
+        let state_diff = &state_update.state_diff;
+        classes.extend(
+            state_diff
+                .declared_classes
+                .iter()
+                .map(|class| (class.class_hash, class.compiled_class_hash)),
+        );
+        legacy += state_diff.old_declared_contracts.len();
+    }
+
+    Ok((classes, legacy))
+}
+
+/// A contract deployed within a range, as listed by [`deployments`].
+#[derive(Serialize)]
+pub struct Deployment {
+    pub block: u64,
+    pub address: FieldElement,
+    pub class_hash: FieldElement,
+}
+
+/// Every contract deployed in `block_range`, in block order.
+pub async fn deployments(
+    provider: &SequencerGatewayProvider,
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    fetch: &FetchConfig,
+) -> anyhow::Result<Vec<Deployment>> {
+    let mut deployments = Vec::new();
+
+    for i in block_range {
+        bar.inc(1);
+
+        let state_update = get_state_update(provider, BlockId::Number(i), fetch).await?;
+        deployments.extend(
+            state_update
+                .state_diff
+                .deployed_contracts
+                .iter()
+                .map(|contract| Deployment {
+                    block: i,
+                    address: contract.address,
+                    class_hash: contract.class_hash,
+                }),
+        );
+    }
+
+    Ok(deployments)
+}
+
+/// Blocks of `block_range` whose state diff touches the storage of `contract_address`, in
+/// ascending order. Nothing is accumulated and no trie is built.
+pub async fn blocks_touching_contract(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    fetch: &FetchConfig,
+) -> anyhow::Result<Vec<u64>> {
+    let mut blocks = Vec::new();
+
+    for i in block_range {
+        bar.inc(1);
+
+        let state_update = get_state_update(provider, BlockId::Number(i), fetch).await?;
+        if state_update
+            .state_diff
+            .storage_diffs
+            .contains_key(&contract_address)
+        {
+            blocks.push(i);
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// Root of whatever is already accumulated for `contract_address`, e.g. from a snapshot.
+pub async fn current_root(
+    contract_address: FieldElement,
+    config: &RunConfig,
+) -> anyhow::Result<Felt> {
+    if CONTRACT_STORAGE
+        .read()
+        .await
+        .contains_key(&contract_address)
+    {
+        Ok(
+            storage_root(contract_address, &ProgressBar::hidden(), false, config)
+                .await?
+                .0,
+        )
+    } else {
+        Ok(Felt::ZERO)
+    }
+}
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RATE_LIMITED_DELAY: Duration = Duration::from_secs(120);
+
+/// Where state updates come from: the sequencer gateway, or a scripted provider in tests.
+pub trait StateUpdateSource {
+    fn state_update(
+        &self,
+        block: BlockId,
+    ) -> impl Future<Output = Result<StateUpdate, ProviderError>> + Send;
+}
+
+impl StateUpdateSource for SequencerGatewayProvider {
+    fn state_update(
+        &self,
+        block: BlockId,
+    ) -> impl Future<Output = Result<StateUpdate, ProviderError>> + Send {
+        self.get_state_update(block)
+    }
+}
+
+pub async fn get_state_update(
+    provider: &impl StateUpdateSource,
+    block: BlockId,
+    fetch: &FetchConfig,
+) -> anyhow::Result<StateUpdate> {
+    let mut retries = 15;
+    let mut saved_raw = false;
+    let mut delay = fetch.retry_delay;
+
+    while retries > 0 {
+        if let Some(rate_limiter) = &fetch.rate_limiter {
+            rate_limiter.wait().await;
+        }
+
+        match provider.state_update(block).await {
+            Ok(state_update) => match check_not_stale(block, &state_update).await {
+                Ok(()) => return Ok(state_update),
+                // Caches eventually catch up, retry like any other failure
+                Err(e) => print_result(
+                    &fetch.bar,
+                    format!("⚠️ block {}: {e:#}, retrying", block_label(block)),
+                ),
+            },
+            Err(ProviderError::RateLimited) => {
+                // The gateway's quota wins over --rps, back off until it lets us through
+                delay = (delay * 2).min(MAX_RATE_LIMITED_DELAY);
+                print_result(
+                    &fetch.bar,
+                    format!(
+                        "⚠️ block {}: rate limited, retrying in {delay:?}",
+                        block_label(block)
+                    ),
+                );
+            }
+            Err(e) => {
+                if let (Some(dir), false) = (&fetch.save_raw_on_error, saved_raw) {
+                    saved_raw = true;
+                    match save_raw_state_update(fetch, block, dir).await {
+                        Ok(path) => print_result(
+                            &fetch.bar,
+                            format!(
+                                "⚠️ block {}: {e}, raw response saved to {}",
+                                block_label(block),
+                                path.display()
+                            ),
+                        ),
+                        Err(save_error) => print_result(
+                            &fetch.bar,
+                            format!(
+                                "⚠️ block {}: {e}, and saving the raw response failed: \
+                                {save_error:#}",
+                                block_label(block)
+                            ),
+                        ),
+                    }
+                }
+            }
+        }
+
+        retries -= 1;
+        tokio::time::sleep(delay).await;
+    }
+
+    Err(anyhow!(
+        "Failed to retrieve state update for block {}",
+        block_label(block)
+    ))
+}
+
+/// Checks that `state_update` really is the one of `block`, as far as it can tell since
+/// state updates don't carry their block number: blocks requested by hash must have that
+/// hash, and blocks requested by number must start from the state root the previous block
+/// ended at, when it was fetched. A gateway serving a cached, older state update fails
+/// the check instead of silently corrupting the accumulated storage.
+pub async fn check_not_stale(block: BlockId, state_update: &StateUpdate) -> anyhow::Result<()> {
+    match block {
+        BlockId::Hash(hash) => match state_update.block_hash {
+            Some(returned) if returned != hash => {
+                return Err(anyhow!(
+                    "gateway returned the state update of block {returned:#x}"
+                ))
+            }
+            _ => {}
+        },
+        BlockId::Number(n) => {
+            let mut state_roots = STATE_ROOTS.write().await;
+            if let Some(previous) = n.checked_sub(1).and_then(|p| state_roots.get(&p)) {
+                if *previous != state_update.old_root {
+                    return Err(anyhow!(
+                        "stale state update, its old root {:#x} is not the new root {previous:#x} \
+                        of block {}",
+                        state_update.old_root,
+                        n - 1
+                    ));
+                }
+            }
+            if let Some(new_root) = state_update.new_root {
+                state_roots.insert(n, new_root);
+            }
+        }
+        BlockId::Pending | BlockId::Latest => {}
+    }
+
+    Ok(())
+}
+
+/// Fetches the state update of `block` as raw JSON, bypassing starknet-rs' models, and
+/// writes it to `dir`. Useful to diagnose gateway format changes the models can't parse.
+async fn save_raw_state_update(
+    fetch: &FetchConfig,
+    block: BlockId,
+    dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    let query = match block {
+        BlockId::Number(n) => format!("blockNumber={n}"),
+        BlockId::Hash(hash) => format!("blockHash={hash:#x}"),
+        BlockId::Pending => "blockNumber=pending".to_string(),
+        BlockId::Latest => "blockNumber=latest".to_string(),
+    };
+    let url = format!("{}/get_state_update?{query}", fetch.feeder_gateway_url);
+    if let Some(rate_limiter) = &fetch.rate_limiter {
+        rate_limiter.wait().await;
+    }
+    let raw = fetch.client.get(&url).send().await?.text().await?;
+
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+    let path = dir.join(format!("state_update_{}.json", block_label(block)));
+    std::fs::write(&path, raw).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    Ok(path)
+}
+
+/// Address of the contract deployed by the transaction `transaction_hash`, which must be a
+/// `DEPLOY` or `DEPLOY_ACCOUNT` transaction.
+pub async fn deployed_contract(
+    provider: &SequencerGatewayProvider,
+    transaction_hash: FieldElement,
+) -> anyhow::Result<FieldElement> {
+    let transaction = provider
+        .get_transaction(transaction_hash)
+        .await
+        .with_context(|| format!("Failed to retrieve transaction {transaction_hash:#x}"))?;
+
+    match transaction.r#type {
+        Some(TransactionType::Deploy(deploy)) => Ok(deploy.contract_address),
+        Some(TransactionType::DeployAccount(deploy)) => deploy.contract_address.ok_or_else(|| {
+            anyhow!("Deploy account transaction {transaction_hash:#x} has no contract address")
+        }),
+        Some(_) => Err(anyhow!(
+            "Transaction {transaction_hash:#x} is not a deploy transaction"
+        )),
+        None => Err(anyhow!("Transaction {transaction_hash:#x} was not found")),
+    }
+}
+
+/// Human readable form of `block`, e.g. `42` or `0x1a2b..`.
+pub fn block_label(block: BlockId) -> String {
+    match block {
+        BlockId::Number(n) => n.to_string(),
+        BlockId::Hash(hash) => format!("{hash:#x}"),
+        BlockId::Pending => "pending".to_string(),
+        BlockId::Latest => "latest".to_string(),
+    }
+}
+
+/// Prints a result line to stdout, clearing the bar while printing and redrawing it below.
+/// Unlike `bar.println`, the line is kept when the bar is hidden or draws to a stream that
+/// is not a terminal.
+pub fn print_result(bar: &ProgressBar, line: impl Display) {
+    bar.suspend(|| println!("{line}"));
+}
+
+/// What merging a diff did to the accumulated storage.
+#[derive(Default, Debug)]
+pub struct StorageChange {
+    /// Whether any value actually changed
+    pub changed: bool,
+    /// Keys whose nonzero value was set to zero
+    pub cleared: Vec<FieldElement>,
+}
+
+/// Merges `storage_updates` into the accumulated storage of `contract_address`.
+pub async fn save_storage_update(
+    contract_address: FieldElement,
+    storage_updates: &[StorageDiff],
+) -> StorageChange {
+    let storage_new: HashMap<FieldElement, FieldElement> = storage_updates
+        .iter()
+        .map(|StorageDiff { key, value }| (*key, *value))
+        .collect();
+
+    let mut contract_storage = CONTRACT_STORAGE.write().await;
+
+    match contract_storage.get(&contract_address) {
+        Some(storage_old) => {
+            let mut storage_old = storage_old.write().await;
+            let mut change = StorageChange::default();
+
+            for (key, value) in storage_new {
+                let previous = storage_old.insert(key, value);
+                change.changed |= previous != Some(value);
+                if value == FieldElement::ZERO
+                    && previous.is_some_and(|previous| previous != FieldElement::ZERO)
+                {
+                    change.cleared.push(key);
+                }
+            }
+
+            change
+        }
+        None => {
+            contract_storage.insert(contract_address, RwLock::new(storage_new));
+            StorageChange {
+                changed: true,
+                cleared: Vec::new(),
+            }
+        }
+    }
+}
+
+/// Records the class hash and nonce changes of `contract_address` in `state_diff`.
+pub async fn save_contract_data(contract_address: FieldElement, state_diff: &StateDiff) {
+    let deployed = state_diff
+        .deployed_contracts
+        .iter()
+        .filter(|contract| contract.address == contract_address)
+        .map(|contract| contract.class_hash);
+    let replaced = state_diff
+        .replaced_classes
+        .iter()
+        .filter(|contract| contract.address == contract_address)
+        .map(|contract| contract.class_hash);
+    let class_hash = deployed.chain(replaced).last();
+    let nonce = state_diff.nonces.get(&contract_address).copied();
+
+    if class_hash.is_none() && nonce.is_none() {
+        return;
+    }
+
+    let mut contract_data = CONTRACT_DATA.write().await;
+    let contract_data = contract_data.entry(contract_address).or_default();
+
+    if let Some(class_hash) = class_hash {
+        contract_data.class_hash = class_hash;
+    }
+    if let Some(nonce) = nonce {
+        contract_data.nonce = nonce;
+    }
+}
+
+/// Leaf of a class in the class trie: `poseidon("CONTRACT_CLASS_LEAF_V0", compiled_class_hash)`.
+pub fn class_trie_leaf(compiled_class_hash: &FieldElement) -> FieldElement {
+    let tag = b"CONTRACT_CLASS_LEAF_V0";
+    let mut bytes = [0u8; 32];
+    bytes[32 - tag.len()..].copy_from_slice(tag);
+
+    to_field_element(&Poseidon::hash(
+        &Felt::from_bytes_be(&bytes),
+        &to_felt(compiled_class_hash),
+    ))
+}
+
+/// Leaf of `contract_address` in the contract trie:
+/// `pedersen(pedersen(pedersen(class_hash, storage_root), nonce), 0)`.
+pub fn contract_leaf_hash(class_hash: &Felt, storage_root: &Felt, nonce: &Felt) -> Felt {
+    let hash = Pedersen::hash(class_hash, storage_root);
+    let hash = Pedersen::hash(&hash, nonce);
+    Pedersen::hash(&hash, &Felt::ZERO)
+}
+
+/// Bonsai namespaces each trie by prefixing its db keys with the identifier, so an empty
+/// identifier would leave the trie unprefixed. Only emptiness is checked: bonsai-trie sets
+/// no length limit that we know of.
+pub fn validate_identifier(identifier: &[u8]) -> anyhow::Result<()> {
+    if identifier.is_empty() {
+        return Err(anyhow!("Bonsai identifier must not be empty"));
+    }
+
+    Ok(())
+}
+
+/// Bonsai root of the accumulated storage of `contract_address` and its number of keys.
+/// With `log_keys`, each key is printed above `bar` first, up to
+/// `config.bonsai.max_key_logs` of them if set.
+pub async fn storage_root(
+    contract_address: FieldElement,
+    bar: &ProgressBar,
+    log_keys: bool,
+    config: &RunConfig,
+) -> anyhow::Result<(Felt, usize)> {
+    let (root, keys, _, _) = storage_root_timed(contract_address, bar, log_keys, config).await?;
+    Ok((root, keys))
+}
+
+/// Same as [`storage_root`], also returning how many of the keys are zero and the hashing
+/// time of [`bonsai_root_timed`].
+pub async fn storage_root_timed(
+    contract_address: FieldElement,
+    bar: &ProgressBar,
+    log_keys: bool,
+    config: &RunConfig,
+) -> anyhow::Result<(Felt, usize, usize, Duration)> {
+    // Select the leaves before opening the db so no trie state is held across an await
+    let storage = selected_storage(contract_address, config).await;
+    if config.strict_keys {
+        check_keys_fit(storage.iter().map(|(key, _)| key))?;
+    }
+    if log_keys {
+        let max = config.bonsai.max_key_logs.unwrap_or(usize::MAX);
+        for (key, value) in storage.iter().take(max) {
+            print_result(bar, format!("🔑 {key:#x} -> {value:#x}"));
+        }
+        if storage.len() > max {
+            print_result(bar, format!("🔑 … and {} more", storage.len() - max));
+        }
+    }
+    let (root, hash_time) = bonsai_root_timed(
+        storage.iter().map(|(key, value)| (key, value)),
+        bar,
+        &config.bonsai,
+    )?;
+
+    let zeros = storage
+        .iter()
+        .filter(|(_, value)| *value == FieldElement::ZERO)
+        .count();
+
+    Ok((root, storage.len(), zeros, hash_time))
+}
+
+/// The accumulated storage of `contract_address` which goes into the trie, after the key
+/// filter and leaf transform of `config`.
+pub async fn selected_storage(
+    contract_address: FieldElement,
+    config: &RunConfig,
+) -> Vec<(FieldElement, FieldElement)> {
+    let contract_storage = CONTRACT_STORAGE.read().await;
+    let contract_storage = contract_storage.get(&contract_address).unwrap();
+    let contract_storage = contract_storage.read().await;
+
+    let mut storage: Vec<_> = contract_storage
+        .iter()
+        .filter(|(key, _)| config.filter.matches(key))
+        .map(|(key, value)| (*key, config.leaf_transform.apply(*value)))
+        .collect();
+    if config.deterministic {
+        storage.sort_unstable_by_key(|(key, _)| *key);
+    }
+    storage
+}
+
+/// What went into the trie for one key, as reported by [`compare_roots`].
+#[derive(Clone, Copy, Debug)]
+pub struct KeyReport {
+    pub key: FieldElement,
+    pub value: FieldElement,
+    /// Length of the trie path the key was sliced to, always [`TRIE_KEY_LEN`]
+    pub sliced_key_len: usize,
+}
+
+/// Bonsai and reference roots of the same storage, as returned by [`compare_roots`].
+#[derive(Debug)]
+pub struct Comparison {
+    pub bonsai: Felt,
+    pub reference: Felt,
+    /// Every inserted key, zero values included, if requested
+    pub keys: Vec<KeyReport>,
+}
+
+impl Comparison {
+    pub fn matches(&self) -> bool {
+        self.bonsai == self.reference
+    }
+}
+
+/// Computes the Bonsai and reference roots of `storage`, and with `report_keys` a
+/// [`KeyReport`] for each of its keys, which costs memory on large storages.
+pub fn compare_roots<'a>(
+    storage: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>,
+    options: &BonsaiOptions,
+    report_keys: bool,
+) -> anyhow::Result<Comparison> {
+    let storage: Vec<_> = storage.into_iter().collect();
+
+    let keys = if report_keys {
+        storage
+            .iter()
+            .map(|(key, value)| KeyReport {
+                key: **key,
+                value: **value,
+                sliced_key_len: trie_key(key).len(),
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    Ok(Comparison {
+        bonsai: bonsai_root(storage.iter().copied(), &ProgressBar::hidden(), options)?,
+        reference: reference::root(storage.iter().copied()),
+        keys,
+    })
+}
+
+/// Inserts `storage` into a fresh Bonsai trie and commits it. Warnings are printed above
+/// `bar`.
+pub fn bonsai_root<'a>(
+    storage: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>,
+    bar: &ProgressBar,
+    options: &BonsaiOptions,
+) -> anyhow::Result<Felt> {
+    Ok(bonsai_root_timed(storage, bar, options)?.0)
+}
+
+/// Same as [`bonsai_root`], also returning the time spent hashing the trie, i.e. in the
+/// commit and `root_hash` calls, excluding insertions.
+pub fn bonsai_root_timed<'a>(
+    storage: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>,
+    bar: &ProgressBar,
+    options: &BonsaiOptions,
+) -> anyhow::Result<(Felt, Duration)> {
+    bonsai_root_hashed::<Pedersen>(storage, bar, options)
+}
+
+/// Bonsai root of the class trie whose `leaves` map class hashes to [`class_trie_leaf`]s.
+pub fn bonsai_class_root<'a>(
+    leaves: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>,
+    bar: &ProgressBar,
+) -> anyhow::Result<Felt> {
+    Ok(bonsai_root_hashed::<Poseidon>(leaves, bar, &BonsaiOptions::default())?.0)
+}
+
+/// Warns once that [`BonsaiOptions::check_uncommitted`] has nothing to check.
+static UNCOMMITTED_UNSUPPORTED: Once = Once::new();
+
+fn bonsai_root_hashed<'a, H: StarkHash + Send + Sync>(
+    storage: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>,
+    bar: &ProgressBar,
+    options: &BonsaiOptions,
+) -> anyhow::Result<(Felt, Duration)> {
+    let tempdir = tempdir().context(
+        "Failed to create a temporary directory for RocksDB, \
+        check that the system temp dir exists, is writable and is not full",
+    )?;
+    let db = create_rocks_db(tempdir.path()).map_err(|e| rocks_db_error(tempdir.path(), e))?;
+    let config = options.storage.config();
+    let mut bonsai_storage: BonsaiStorage<_, _, H> =
+        BonsaiStorage::new(RocksDB::new(&db, RocksDBConfig::default()), config)
+            .map_err(|e| anyhow!("Failed to create Bonsai storage: {e:?}"))?;
+
+    let mut id_builder = BasicIdBuilder::new();
+    for (key, value) in storage {
+        let key = trie_key(key);
+        let value = to_felt(value);
+
+        bonsai_storage
+            .insert(IDENTIFIER, &key, &value)
+            .map_err(|e| anyhow!("Failed to insert into Bonsai storage: {e:?}"))?;
+        if options.commit_each_insert {
+            bonsai_storage
+                .commit(id_builder.new_id())
+                .map_err(|e| anyhow!("Failed to commit to Bonsai storage: {e:?}"))?;
+        }
+    }
+
+    // Whether `root_hash` sees uncommitted inserts is not documented for the fork we
+    // depend on, it may only report the last commit. This trie is fresh, so that would be
+    // the empty root
+    let uncommitted = if options.check_uncommitted {
+        Some(
+            bonsai_storage
+                .root_hash(IDENTIFIER)
+                .map_err(|e| anyhow!("Failed to retrieve uncommitted root hash: {e:?}"))?,
+        )
+    } else {
+        None
+    };
+
+    let start = std::time::Instant::now();
+    bonsai_storage
+        .commit(id_builder.new_id())
+        .map_err(|e| anyhow!("Failed to commit to Bonsai storage: {e:?}"))?;
+    let root = bonsai_storage
+        .root_hash(IDENTIFIER)
+        .map_err(|e| anyhow!("Failed to retrieve root hash: {e:?}"))?;
+    let hash_time = start.elapsed();
+
+    match uncommitted {
+        Some(uncommitted) if uncommitted == Felt::ZERO && root != Felt::ZERO => {
+            UNCOMMITTED_UNSUPPORTED.call_once(|| {
+                print_result(
+                    bar,
+                    "⚠️ Bonsai only reports committed roots, --check-uncommitted falls back \
+                    to the committed root",
+                )
+            });
+            Ok((root, hash_time))
+        }
+        Some(uncommitted) if uncommitted != root => Err(anyhow!(
+            "Uncommitted root {uncommitted:#x} differs from committed root {root:#x}"
+        )),
+        _ => Ok((root, hash_time)),
+    }
+}
+
+/// Explains the usual causes of RocksDB failing to open at `path`.
+pub fn rocks_db_error(path: &Path, e: impl Debug) -> anyhow::Error {
+    let e = format!("{e:?}");
+    let hint = if e.contains("LOCK") {
+        format!(
+            "another process holds its lock; if none is running, the lock is stale and \
+            removing {} fixes it",
+            path.join("LOCK").display()
+        )
+    } else {
+        "check that it is writable and that the disk is not full".to_string()
+    };
+
+    anyhow!("Failed to open RocksDB at {}: {e}, {hint}", path.display())
+}
+
+/// Drops the 5 leading bits of the 256-bit big-endian felt, leaving a 251-bit trie key.
+///
+/// 5 is not arbitrary: storage addresses are reduced modulo `2^251 - 256`, so their 5
+/// leading bits are always zero and the remaining 251 are exactly the height of
+/// Starknet's storage tries. Any other offset either drops significant bits (merging
+/// distinct keys) or cannot yield 251 bits at all; see [`offset_key`].
+pub fn trie_key(key: &FieldElement) -> BitVec<u8, Msb0> {
+    let key = key.to_bytes_be().view_bits()[5..].to_owned();
+    debug_assert_eq!(key.len(), TRIE_KEY_LEN);
+    key
+}
+
+/// Fails unless every key is below 2^251, listing a few of those which are not. Their top
+/// bits would be dropped by [`trie_key`], silently merging them with other keys.
+pub fn check_keys_fit<'a>(keys: impl IntoIterator<Item = &'a FieldElement>) -> anyhow::Result<()> {
+    let oversized: Vec<_> = keys
+        .into_iter()
+        .filter(|key| key.to_bytes_be().view_bits::<Msb0>()[..5].any())
+        .collect();
+
+    if oversized.is_empty() {
+        return Ok(());
+    }
+    let sample: Vec<_> = oversized
+        .iter()
+        .take(5)
+        .map(|key| format!("{key:#x}"))
+        .collect();
+    Err(anyhow!(
+        "{} keys do not fit in {TRIE_KEY_LEN} bits, e.g. {}",
+        oversized.len(),
+        sample.join(", ")
+    ))
+}
+
+/// `key` as if it were sliced at `offset` instead of 5, i.e. the felt whose [`trie_key`]
+/// is bits `offset..offset + 251` of `key`. Only offsets up to 5 fit in a felt; below 5,
+/// the last `5 - offset` bits of the key are dropped, so distinct keys may collide.
+pub fn offset_key(key: &FieldElement, offset: usize) -> FieldElement {
+    assert!(
+        offset <= 5,
+        "slicing at bit {offset} leaves less than 251 bits"
+    );
+
+    let bytes = key.to_bytes_be();
+    let mut sliced = [0u8; 32];
+    sliced.view_bits_mut::<Msb0>()[256 - TRIE_KEY_LEN..]
+        .copy_from_bitslice(&bytes.view_bits::<Msb0>()[offset..offset + TRIE_KEY_LEN]);
+    FieldElement::from_bytes_be(&sliced).unwrap()
+}
+
+/// Reads a key which is already a trie path: either a string of exactly 251 `0`/`1` bits,
+/// or a hex value below 2^251. The result goes through [`trie_key`] unchanged, since the 5
+/// bits it drops are zero.
+pub fn presliced_key(key: &str) -> anyhow::Result<FieldElement> {
+    if key.len() == TRIE_KEY_LEN && key.chars().all(|c| c == '0' || c == '1') {
+        let mut bytes = [0u8; 32];
+        let bits = &mut bytes.view_bits_mut::<Msb0>()[256 - TRIE_KEY_LEN..];
+        for (i, c) in key.chars().enumerate() {
+            bits.set(i, c == '1');
+        }
+        return Ok(FieldElement::from_bytes_be(&bytes).unwrap());
+    }
+
+    let felt = FieldElement::from_hex_be(key)
+        .map_err(|e| anyhow!("Presliced key {key} is neither 251 bits nor hex: {e}"))?;
+    if felt.to_bytes_be().view_bits::<Msb0>()[..256 - TRIE_KEY_LEN].any() {
+        return Err(anyhow!("Presliced key {key} is longer than 251 bits"));
+    }
+
+    Ok(felt)
+}
+
+pub fn to_field_element(felt: &Felt) -> FieldElement {
+    FieldElement::from_bytes_be(&felt.to_bytes_be()).unwrap()
+}
+
+/// Both libraries are big-endian throughout: `to_bytes_be` puts the most significant byte
+/// first, and trie keys are read most significant bit first, so a felt keeps its value
+/// across the conversion and the low bit of a key is the last bit of its path.
+pub fn to_felt(field_element: &FieldElement) -> Felt {
+    Felt::from_bytes_be(&field_element.to_bytes_be())
+}
+
+/// Storage values cross from starknet-rs' `FieldElement` to Bonsai's `Felt` through their
+/// byte representations. Both must agree on the field, or roots would silently be computed
+/// over different values: check the largest felt round-trips and wraps to zero in both.
+pub fn check_felt_compat() -> anyhow::Result<()> {
+    let max = to_felt(&FieldElement::MAX);
+    if max != Felt::MAX {
+        return Err(anyhow!(
+            "FieldElement::MAX converts to {max:#x} but Felt::MAX is {:#x}, the field moduli differ",
+            Felt::MAX
+        ));
+    }
+
+    if to_field_element(&Felt::MAX) != FieldElement::MAX {
+        return Err(anyhow!(
+            "Felt::MAX does not convert back to FieldElement::MAX"
+        ));
+    }
+
+    if FieldElement::MAX + FieldElement::ONE != FieldElement::ZERO
+        || Felt::MAX + Felt::ONE != Felt::ZERO
+    {
+        return Err(anyhow!(
+            "MAX + 1 does not wrap to zero, the field moduli differ"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Root of the accumulated storage of `contract_address` according to [`reference::root`].
+pub async fn reference_root(contract_address: FieldElement, config: &RunConfig) -> Felt {
+    let storage = selected_storage(contract_address, config).await;
+    reference::root(storage.iter().map(|(key, value)| (key, value)))
+}
+
+/// Order-independent digest of the accumulated storage of `contract_address`: the sum of
+/// `pedersen(key, value)` over every key written so far, zeros included. Two runs which
+/// ingested the same diffs share it, whatever their tries make of them.
+pub async fn storage_digest(contract_address: FieldElement) -> Felt {
+    match CONTRACT_STORAGE.read().await.get(&contract_address) {
+        Some(storage) => storage
+            .read()
+            .await
+            .iter()
+            .fold(Felt::ZERO, |digest, (key, value)| {
+                digest + Pedersen::hash(&to_felt(key), &to_felt(value))
+            }),
+        None => Felt::ZERO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use bonsai_trie::id::BasicId;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    fn felt(value: u64) -> FieldElement {
+        FieldElement::from(value)
+    }
+
+    /// Tries under fixed-length identifiers, as derived from contract addresses, sharing
+    /// one db
+    #[test]
+    fn identifiers_do_not_collide() {
+        let identifiers: Vec<[u8; 32]> = (1..=3u64).map(|i| felt(i).to_bytes_be()).collect();
+        for identifier in &identifiers {
+            validate_identifier(identifier).unwrap();
+        }
+
+        let tempdir = tempdir().unwrap();
+        let db = create_rocks_db(tempdir.path()).unwrap();
+        let mut bonsai_storage: BonsaiStorage<_, _, Pedersen> = BonsaiStorage::new(
+            RocksDB::new(&db, RocksDBConfig::default()),
+            BonsaiStorageConfig::default(),
+        )
+        .unwrap();
+
+        // Same keys in every trie, values depending on the trie
+        let storage = |trie: u64| -> Vec<_> {
+            (0..4u64)
+                .map(|key| (felt(key), felt(trie * 10 + key + 1)))
+                .collect()
+        };
+        for (trie, identifier) in (0..).zip(&identifiers) {
+            for (key, value) in storage(trie) {
+                bonsai_storage
+                    .insert(identifier, &trie_key(&key), &to_felt(&value))
+                    .unwrap();
+            }
+        }
+        bonsai_storage
+            .commit(BasicIdBuilder::new().new_id())
+            .unwrap();
+
+        for (trie, identifier) in (0..).zip(&identifiers) {
+            let storage = storage(trie);
+            assert_eq!(
+                bonsai_storage.root_hash(identifier).unwrap(),
+                reference::root(storage.iter().map(|(key, value)| (key, value))),
+            );
+        }
+        assert!(validate_identifier(b"").is_err());
+    }
+
+    #[test]
+    fn trie_keys_are_251_bits() {
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0x04;
+        let two_to_250 = FieldElement::from_bytes_be(&bytes).unwrap();
+
+        for key in [felt(0), felt(1), two_to_250, FieldElement::MAX] {
+            assert_eq!(trie_key(&key).len(), TRIE_KEY_LEN, "key {key:#x}");
+        }
+    }
+
+    #[test]
+    fn leaf_transforms_keep_engines_in_agreement() {
+        let storage: Vec<_> = (0..16u64)
+            .map(|key| (felt(key * 7919), felt(key % 5)))
+            .collect();
+        let identity = reference::root(storage.iter().map(|(key, value)| (key, value)));
+
+        for transform in [LeafTransform::Pedersen, LeafTransform::Poseidon] {
+            let transformed: Vec<_> = storage
+                .iter()
+                .map(|(key, value)| (*key, transform.apply(*value)))
+                .collect();
+            let transformed = || transformed.iter().map(|(key, value)| (key, value));
+
+            let bonsai = bonsai_root(
+                transformed(),
+                &ProgressBar::hidden(),
+                &BonsaiOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(bonsai, reference::root(transformed()), "{transform:?}");
+            assert_ne!(bonsai, identity, "{transform:?}");
+            assert_eq!(transform.apply(FieldElement::ZERO), FieldElement::ZERO);
+        }
+    }
+
+    /// Root of `contract` after accumulating `blocks` of `(key, value)` writes in order
+    async fn accumulated_root(contract: FieldElement, blocks: &[Vec<(u64, u64)>]) -> Felt {
+        for block in blocks {
+            let diffs: Vec<_> = block
+                .iter()
+                .map(|&(key, value)| StorageDiff {
+                    key: felt(key),
+                    value: felt(value),
+                })
+                .collect();
+            save_storage_update(contract, &diffs).await;
+        }
+        current_root(contract, &RunConfig::default()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn blocks_writing_disjoint_keys_commute() {
+        let blocks = vec![vec![(1, 10), (2, 20)], vec![(3, 30)], vec![(4, 40), (5, 0)]];
+        let reversed: Vec<_> = blocks.iter().rev().cloned().collect();
+
+        assert_eq!(
+            accumulated_root(felt(1191), &blocks).await,
+            accumulated_root(felt(1192), &reversed).await,
+        );
+    }
+
+    #[tokio::test]
+    async fn blocks_writing_the_same_key_do_not_commute() {
+        // The last write to key 2 wins
+        let blocks = vec![vec![(1, 10), (2, 20)], vec![(2, 21)]];
+        let reversed: Vec<_> = blocks.iter().rev().cloned().collect();
+
+        assert_ne!(
+            accumulated_root(felt(1193), &blocks).await,
+            accumulated_root(felt(1194), &reversed).await,
+        );
+    }
+
+    #[test]
+    fn unwritable_db_path_names_the_path() {
+        // A regular file in place of a directory, which even root cannot create a db under
+        let tempdir = tempdir().unwrap();
+        let file = tempdir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let path = file.join("db");
+
+        let e = create_rocks_db(&path)
+            .err()
+            .expect("opened a db under a file");
+        let e = rocks_db_error(&path, e).to_string();
+        assert!(e.contains(&path.display().to_string()), "{e}");
+        assert!(e.contains("check that it is writable"), "{e}");
+    }
+
+    #[test]
+    fn locked_db_suggests_removing_the_lock() {
+        let tempdir = tempdir().unwrap();
+        let _db = create_rocks_db(tempdir.path()).unwrap();
+
+        let e = create_rocks_db(tempdir.path())
+            .err()
+            .expect("opened a locked db");
+        let e = rocks_db_error(tempdir.path(), e).to_string();
+        assert!(e.contains("another process holds its lock"), "{e}");
+    }
+
+    #[test]
+    fn root_survives_reopening_the_db() {
+        let tempdir = tempdir().unwrap();
+        let root = {
+            let db = create_rocks_db(tempdir.path()).unwrap();
+            let mut bonsai_storage: BonsaiStorage<_, _, Pedersen> = BonsaiStorage::new(
+                RocksDB::new(&db, RocksDBConfig::default()),
+                BonsaiStorageConfig::default(),
+            )
+            .unwrap();
+
+            let mut id_builder = BasicIdBuilder::new();
+            for commit in 0..4u64 {
+                for key in 0..8u64 {
+                    bonsai_storage
+                        .insert(
+                            IDENTIFIER,
+                            &trie_key(&felt(key)),
+                            &Felt::from(commit * 8 + key + 1),
+                        )
+                        .unwrap();
+                }
+                bonsai_storage.commit(id_builder.new_id()).unwrap();
+            }
+            bonsai_storage.root_hash(IDENTIFIER).unwrap()
+        };
+
+        let db = create_rocks_db(tempdir.path()).unwrap();
+        let bonsai_storage: BonsaiStorage<BasicId, _, Pedersen> = BonsaiStorage::new(
+            RocksDB::new(&db, RocksDBConfig::default()),
+            BonsaiStorageConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(bonsai_storage.root_hash(IDENTIFIER).unwrap(), root);
+        assert_ne!(root, Felt::ZERO);
+    }
+
+    #[test]
+    fn byte_order_is_big_endian_in_both_libraries() {
+        let asymmetric = 0x0102_0304_0506_0708_u64;
+        assert_eq!(to_felt(&felt(asymmetric)), Felt::from(asymmetric));
+        assert_eq!(to_field_element(&Felt::from(asymmetric)), felt(asymmetric));
+
+        let one = trie_key(&FieldElement::ONE);
+        assert_eq!(one.first_one(), Some(TRIE_KEY_LEN - 1));
+        assert_eq!(one.count_ones(), 1);
+
+        let mut top = [0u8; 32];
+        top[0] = 0x04;
+        let top = trie_key(&FieldElement::from_bytes_be(&top).unwrap());
+        assert_eq!(top.first_one(), Some(0));
+        assert_eq!(top.count_ones(), 1);
+    }
+
+    #[test]
+    fn values_near_the_modulus_round_trip() {
+        let storage: Vec<_> = adversarial::near_modulus_values()
+            .into_iter()
+            .zip(1u64..)
+            .map(|(value, key)| (felt(key), value))
+            .collect();
+        for (_, value) in &storage {
+            assert_eq!(to_field_element(&to_felt(value)), *value);
+        }
+
+        let tempdir = tempdir().unwrap();
+        let db = create_rocks_db(tempdir.path()).unwrap();
+        let mut bonsai_storage: BonsaiStorage<_, _, Pedersen> = BonsaiStorage::new(
+            RocksDB::new(&db, RocksDBConfig::default()),
+            BonsaiStorageConfig::default(),
+        )
+        .unwrap();
+        for (key, value) in &storage {
+            bonsai_storage
+                .insert(IDENTIFIER, &trie_key(key), &to_felt(value))
+                .unwrap();
+        }
+        bonsai_storage
+            .commit(BasicIdBuilder::new().new_id())
+            .unwrap();
+
+        assert_eq!(
+            bonsai_storage.root_hash(IDENTIFIER).unwrap(),
+            reference::root(storage.iter().map(|(key, value)| (key, value)))
+        );
+        for (key, value) in &storage {
+            let read = bonsai_storage.get(IDENTIFIER, &trie_key(key)).unwrap();
+            assert_eq!(read.map(|read| to_field_element(&read)), Some(*value));
+        }
+    }
+
+    /// A value must make the same leaf whichever constructor built it, so that keys and
+    /// values parsed from hex, JSON or bytes agree
+    #[test]
+    fn constructors_make_the_same_leaf() {
+        let mut bytes = [0u8; 32];
+        bytes[24..].copy_from_slice(&0x0102_0304_0506_0708_u64.to_be_bytes());
+        let constructed = [
+            ("u64", felt(0x0102_0304_0506_0708_u64)),
+            (
+                "hex",
+                FieldElement::from_hex_be("0x0102030405060708").unwrap(),
+            ),
+            (
+                "decimal",
+                FieldElement::from_dec_str("72623859790382856").unwrap(),
+            ),
+            ("bytes", FieldElement::from_bytes_be(&bytes).unwrap()),
+            ("Felt", to_field_element(&Felt::from_bytes_be(&bytes))),
+        ];
+
+        let leaf = to_felt(&constructed[0].1).to_bytes_be();
+        let key = felt(42);
+        let root = reference::root([(&key, &constructed[0].1)]);
+        for (constructor, value) in &constructed[1..] {
+            assert_eq!(to_felt(value).to_bytes_be(), leaf, "{constructor}");
+            let storage = [(&key, value)];
+            assert_eq!(reference::root(storage), root, "{constructor}");
+            assert_eq!(
+                bonsai_root(storage, &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap(),
+                root,
+                "{constructor}"
+            );
+        }
+    }
+
+    /// The 251 low bits of `key`, most significant first, built without slicing
+    fn manual_trie_key(key: &FieldElement) -> BitVec<u8, Msb0> {
+        let bytes = key.to_bytes_be();
+        (256 - TRIE_KEY_LEN..256)
+            .map(|bit| (bytes[bit / 8] >> (7 - bit % 8)) & 1 == 1)
+            .collect()
+    }
+
+    /// Bonsai root of `storage` with each key inserted at the path `path_of` gives
+    fn bonsai_root_of_paths(
+        storage: &[(FieldElement, FieldElement)],
+        path_of: impl Fn(&FieldElement) -> BitVec<u8, Msb0>,
+    ) -> Felt {
+        let tempdir = tempdir().unwrap();
+        let db = create_rocks_db(tempdir.path()).unwrap();
+        let mut bonsai_storage: BonsaiStorage<_, _, Pedersen> = BonsaiStorage::new(
+            RocksDB::new(&db, RocksDBConfig::default()),
+            BonsaiStorageConfig::default(),
+        )
+        .unwrap();
+        for (key, value) in storage {
+            let path = path_of(key);
+            assert_eq!(path.len(), TRIE_KEY_LEN);
+            bonsai_storage
+                .insert(IDENTIFIER, &path, &to_felt(value))
+                .unwrap();
+        }
+        bonsai_storage
+            .commit(BasicIdBuilder::new().new_id())
+            .unwrap();
+        bonsai_storage.root_hash(IDENTIFIER).unwrap()
+    }
+
+    /// Reference root of `storage` with each key at the path `path_of` gives
+    fn reference_root_of_paths(
+        storage: &[(FieldElement, FieldElement)],
+        path_of: impl Fn(&FieldElement) -> BitVec<u8, Msb0>,
+    ) -> Felt {
+        reference::root_of_paths(
+            storage
+                .iter()
+                .map(|(key, value)| (path_of(key), to_felt(value))),
+        )
+    }
+
+    /// Keys whose paths are not palindromes, so that reading them backwards moves them
+    fn asymmetric_storage() -> Vec<(FieldElement, FieldElement)> {
+        let mut top = [0u8; 32];
+        top[0] = 0x07;
+        top[31] = 0xff;
+        [
+            felt(1),
+            felt(0xdead_beef),
+            FieldElement::from_bytes_be(&top).unwrap(),
+        ]
+        .into_iter()
+        .map(|key| (key, key + felt(1)))
+        .collect()
+    }
+
+    #[test]
+    fn sliced_and_manual_trie_keys_make_the_same_root() {
+        let storage = asymmetric_storage();
+        let root = reference::root(storage.iter().map(|(key, value)| (key, value)));
+
+        assert_eq!(bonsai_root_of_paths(&storage, trie_key), root);
+        assert_eq!(bonsai_root_of_paths(&storage, manual_trie_key), root);
+        assert_eq!(reference_root_of_paths(&storage, manual_trie_key), root);
+    }
+
+    /// Paths are read most significant bit first. Both engines hash reversed paths alike,
+    /// but only the msb-first order gives the root of the engines' own key conversion
+    #[test]
+    fn only_msb_first_paths_give_the_storage_root() {
+        let storage = asymmetric_storage();
+        let root = reference::root(storage.iter().map(|(key, value)| (key, value)));
+        let lsb_first = |key: &FieldElement| {
+            let mut path = manual_trie_key(key);
+            path.reverse();
+            path
+        };
+
+        let msb = bonsai_root_of_paths(&storage, manual_trie_key);
+        let lsb = bonsai_root_of_paths(&storage, lsb_first);
+        assert_eq!(msb, reference_root_of_paths(&storage, manual_trie_key));
+        assert_eq!(lsb, reference_root_of_paths(&storage, lsb_first));
+        assert_eq!(msb, root);
+        assert_ne!(lsb, root);
+    }
+
+    /// State update of a block touching nothing, from `old_root` to `new_root`
+    fn empty_state_update(old_root: u64, new_root: u64) -> StateUpdate {
+        serde_json::from_value(serde_json::json!({
+            "block_hash": "0x1",
+            "new_root": format!("{new_root:#x}"),
+            "old_root": format!("{old_root:#x}"),
+            "state_diff": {
+                "storage_diffs": {},
+                "deployed_contracts": [],
+                "old_declared_contracts": [],
+                "declared_classes": [],
+                "nonces": {},
+                "replaced_classes": [],
+            },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn stale_state_updates_are_rejected() {
+        let block = 1_860_000;
+        check_not_stale(BlockId::Number(block), &empty_state_update(1, 2))
+            .await
+            .unwrap();
+
+        // Block 1_860_001 must start from the root block 1_860_000 ended at
+        assert!(
+            check_not_stale(BlockId::Number(block + 1), &empty_state_update(1, 3))
+                .await
+                .is_err()
+        );
+        check_not_stale(BlockId::Number(block + 1), &empty_state_update(2, 3))
+            .await
+            .unwrap();
+
+        assert!(
+            check_not_stale(BlockId::Hash(felt(2)), &empty_state_update(2, 3))
+                .await
+                .is_err()
+        );
+    }
+
+    /// State update of a block writing `diffs` to `contract`, or touching no storage at all
+    /// if `diffs` is empty
+    fn scripted_state_update(
+        old_root: u64,
+        new_root: u64,
+        contract: FieldElement,
+        diffs: &[(u64, u64)],
+    ) -> StateUpdate {
+        let mut state_update = empty_state_update(old_root, new_root);
+        if !diffs.is_empty() {
+            let diffs = diffs
+                .iter()
+                .map(|&(key, value)| StorageDiff {
+                    key: felt(key),
+                    value: felt(value),
+                })
+                .collect();
+            state_update
+                .state_diff
+                .storage_diffs
+                .insert(contract, diffs);
+        }
+        state_update
+    }
+
+    /// Provider serving scripted state updates: each request for a block takes the next
+    /// one queued for it, and fails once there are none left.
+    #[derive(Default)]
+    struct MockProvider {
+        blocks: std::sync::Mutex<HashMap<u64, VecDeque<StateUpdate>>>,
+    }
+
+    impl MockProvider {
+        fn push(&self, block: u64, state_update: StateUpdate) {
+            self.blocks
+                .lock()
+                .unwrap()
+                .entry(block)
+                .or_default()
+                .push_back(state_update);
+        }
+
+        fn queued(&self, block: u64) -> usize {
+            self.blocks
+                .lock()
+                .unwrap()
+                .get(&block)
+                .map_or(0, VecDeque::len)
+        }
+    }
+
+    impl StateUpdateSource for MockProvider {
+        fn state_update(
+            &self,
+            block: BlockId,
+        ) -> impl Future<Output = Result<StateUpdate, ProviderError>> + Send {
+            let state_update = match block {
+                BlockId::Number(n) => self
+                    .blocks
+                    .lock()
+                    .unwrap()
+                    .get_mut(&n)
+                    .and_then(VecDeque::pop_front),
+                _ => None,
+            };
+            // Any error the retry loop has no special case for
+            std::future::ready(state_update.ok_or(ProviderError::ArrayLengthMismatch))
+        }
+    }
+
+    fn mock_fetch() -> FetchConfig {
+        FetchConfig {
+            retry_delay: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_state_updates_are_fetched_again() {
+        let block = 1_870_000;
+        let provider = MockProvider::default();
+        provider.push(block, empty_state_update(1, 2));
+        // A cached copy of an older block first, then the real one
+        provider.push(block + 1, empty_state_update(7, 8));
+        provider.push(block + 1, empty_state_update(2, 3));
+
+        get_state_update(&provider, BlockId::Number(block), &mock_fetch())
+            .await
+            .unwrap();
+        let state_update = get_state_update(&provider, BlockId::Number(block + 1), &mock_fetch())
+            .await
+            .unwrap();
+        assert_eq!(state_update.old_root, felt(2));
+        assert_eq!(provider.queued(block + 1), 0);
+
+        // Nothing left to serve: every retry fails
+        assert!(
+            get_state_update(&provider, BlockId::Number(block + 1), &mock_fetch())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn process_range_accumulates_scripted_blocks() {
+        let contract = felt(194);
+        let start = 1_880_000;
+        let provider = MockProvider::default();
+        let blocks: [&[(u64, u64)]; 4] = [&[(1, 10), (2, 20)], &[], &[(1, 0), (3, 30)], &[(2, 21)]];
+        for (i, diffs) in (0..).zip(blocks) {
+            provider.push(
+                start + i,
+                scripted_state_update(i + 1, i + 2, contract, diffs),
+            );
+        }
+
+        let config = RunConfig {
+            fetch: mock_fetch(),
+            reference_check: true,
+            root_delta: true,
+            ..Default::default()
+        };
+        let mut roots = Vec::new();
+        let mut on_block = |result: &BlockResult| roots.push((result.block, result.root.root));
+        let stats = process_range(
+            &provider,
+            contract,
+            start..start + 4,
+            &ProgressBar::hidden(),
+            &config,
+            Some(&mut on_block as BlockHook),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stats.blocks, 4);
+        assert_eq!(stats.empty_blocks, 1);
+        assert_eq!(stats.blocks_with_diffs, 3);
+        assert_eq!(stats.keys_cleared, 1);
+        assert_eq!(stats.mismatches, 0);
+
+        let after = |storage: &[(u64, u64)]| {
+            let storage: Vec<_> = storage
+                .iter()
+                .map(|&(key, value)| (felt(key), felt(value)))
+                .collect();
+            reference::root(storage.iter().map(|(key, value)| (key, value)))
+        };
+        assert_eq!(
+            roots,
+            [
+                (start, after(&[(1, 10), (2, 20)])),
+                (start + 2, after(&[(2, 20), (3, 30)])),
+                (start + 3, after(&[(2, 21), (3, 30)])),
+            ]
+        );
+
+        // The gateway has nothing past the script
+        assert!(process_range(
+            &provider,
+            contract,
+            start + 4..start + 5,
+            &ProgressBar::hidden(),
+            &config,
+            None,
+        )
+        .await
+        .is_err());
+    }
+
+    /// Drives its own runtime, as the stream's task is spawned
+    #[test]
+    fn process_range_stream_yields_blocks_in_order_until_an_error() {
+        let contract = felt(105);
+        let start = 1_890_000;
+        let provider = MockProvider::default();
+        // Block start + 2 is missing, start + 3 must never be reached
+        for i in [0, 1, 3] {
+            provider.push(
+                start + i,
+                scripted_state_update(i + 1, i + 2, contract, &[(i + 1, i + 10)]),
+            );
+        }
+        let config = RunConfig {
+            fetch: mock_fetch(),
+            ..Default::default()
+        };
+
+        let results: Vec<_> = tokio::runtime::Runtime::new().unwrap().block_on(
+            process_range_stream(
+                provider,
+                contract,
+                start..start + 4,
+                ProgressBar::hidden(),
+                config,
+            )
+            .collect(),
+        );
+
+        let blocks: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().map(|result| result.block).ok())
+            .collect();
+        assert_eq!(blocks, [Some(start), Some(start + 1), None]);
+    }
+
+    #[tokio::test]
+    async fn empty_state_diff_changes_nothing() {
+        let contract = felt(190);
+        let mut root = Felt::ZERO;
+
+        let result = apply_state_update(
+            &empty_state_update(1, 1),
+            contract,
+            1,
+            &ProgressBar::hidden(),
+            &RunConfig::default(),
+            &mut root,
+        )
+        .await
+        .unwrap();
+
+        assert!(result.is_none());
+        assert_eq!(root, Felt::ZERO);
+        assert!(!CONTRACT_STORAGE.read().await.contains_key(&contract));
+    }
+
+    /// Keys whose first byte is `region`, so that their hex starts with it
+    fn region_key(region: u8, low: u8) -> FieldElement {
+        let mut bytes = [0u8; 32];
+        bytes[0] = region;
+        bytes[31] = low;
+        FieldElement::from_bytes_be(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn key_prefix_partitions_storage() {
+        let contract = felt(103);
+        let diffs: Vec<_> = [0x00, 0x01, 0x02]
+            .into_iter()
+            .flat_map(|region| (1..=3).map(move |low| region_key(region, low)))
+            .map(|key| StorageDiff {
+                key,
+                value: key + FieldElement::ONE,
+            })
+            .collect();
+        save_storage_update(contract, &diffs).await;
+
+        let mut seen = 0;
+        for (prefix, region) in [("00", 0x00), ("01", 0x01), ("02", 0x02)] {
+            let config = RunConfig {
+                filter: KeyFilter {
+                    prefix: Some(prefix.to_string()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let mut selected = selected_storage(contract, &config).await;
+            selected.sort();
+            let expected: Vec<_> = (1..=3)
+                .map(|low| region_key(region, low))
+                .map(|key| (key, key + FieldElement::ONE))
+                .collect();
+            assert_eq!(selected, expected, "prefix {prefix}");
+            seen += selected.len();
+
+            let storage = || selected.iter().map(|(key, value)| (key, value));
+            assert_eq!(
+                bonsai_root(storage(), &ProgressBar::hidden(), &config.bonsai).unwrap(),
+                reference::root(storage()),
+            );
+        }
+        assert_eq!(seen, diffs.len());
+    }
+
+    #[test]
+    fn committing_an_empty_trie_gives_the_zero_root() {
+        let empty: [(&FieldElement, &FieldElement); 0] = [];
+        assert_eq!(reference::root(empty), Felt::ZERO);
+        assert_eq!(
+            bonsai_root(empty, &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap(),
+            Felt::ZERO
+        );
+
+        let tempdir = tempdir().unwrap();
+        let db = create_rocks_db(tempdir.path()).unwrap();
+        let mut bonsai_storage: BonsaiStorage<_, _, Pedersen> = BonsaiStorage::new(
+            RocksDB::new(&db, RocksDBConfig::default()),
+            BonsaiStorageConfig::default(),
+        )
+        .unwrap();
+        let mut id_builder = BasicIdBuilder::new();
+        bonsai_storage.commit(id_builder.new_id()).unwrap();
+        assert_eq!(bonsai_storage.root_hash(IDENTIFIER).unwrap(), Felt::ZERO);
+
+        // And again, on a trie which has already been committed empty
+        bonsai_storage.commit(id_builder.new_id()).unwrap();
+        assert_eq!(bonsai_storage.root_hash(IDENTIFIER).unwrap(), Felt::ZERO);
+    }
+
+    #[test]
+    fn deleting_keys_never_inserted_changes_nothing() {
+        for name in ["phantom-deletes", "mixed-phantom-deletes"] {
+            let pattern = adversarial::patterns()
+                .into_iter()
+                .find(|pattern| pattern.name == name)
+                .unwrap();
+            assert!(pattern
+                .storage
+                .iter()
+                .any(|(_, value)| *value == FieldElement::ZERO));
+            let inserts: Vec<_> = pattern
+                .storage
+                .iter()
+                .filter(|(_, value)| *value != FieldElement::ZERO)
+                .collect();
+            let storage = || pattern.storage.iter().map(|(key, value)| (key, value));
+            let expected = reference::root(inserts.iter().map(|(key, value)| (key, value)));
+            if name == "phantom-deletes" {
+                assert_eq!(expected, Felt::ZERO);
+            }
+
+            assert_eq!(reference::root(storage()), expected, "{name}");
+            assert_eq!(
+                bonsai_root(storage(), &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap(),
+                expected,
+                "{name}"
+            );
+        }
+    }
+
+    /// After a commit, the trie holds a leaf for every key set to a nonzero value, holding
+    /// that value, and no leaf for keys cleared or never set
+    #[test]
+    fn committed_leaves_are_exactly_the_nonzero_keys() {
+        let writes = [(1, 10), (2, 20), (3, 30), (4, 40), (2, 21), (3, 0), (5, 0)];
+        let storage: HashMap<_, _> = writes
+            .iter()
+            .map(|&(key, value)| (felt(key), felt(value)))
+            .collect();
+
+        let tempdir = tempdir().unwrap();
+        let db = create_rocks_db(tempdir.path()).unwrap();
+        let mut bonsai_storage: BonsaiStorage<_, _, Pedersen> = BonsaiStorage::new(
+            RocksDB::new(&db, RocksDBConfig::default()),
+            BonsaiStorageConfig::default(),
+        )
+        .unwrap();
+        for (key, value) in writes {
+            bonsai_storage
+                .insert(IDENTIFIER, &trie_key(&felt(key)), &Felt::from(value))
+                .unwrap();
+        }
+        bonsai_storage
+            .commit(BasicIdBuilder::new().new_id())
+            .unwrap();
+
+        let leaves: HashMap<_, _> = (0..8u64)
+            .filter_map(|key| {
+                bonsai_storage
+                    .get(IDENTIFIER, &trie_key(&felt(key)))
+                    .unwrap()
+                    .map(|value| (felt(key), to_field_element(&value)))
+            })
+            .collect();
+        let expected: HashMap<_, _> = [(1, 10), (2, 21), (4, 40)]
+            .into_iter()
+            .map(|(key, value)| (felt(key), felt(value)))
+            .collect();
+        assert_eq!(leaves, expected);
+        assert_eq!(reference::shape(&storage).leaves, expected.len());
+        assert_eq!(
+            bonsai_storage.root_hash(IDENTIFIER).unwrap(),
+            reference::root(&expected)
+        );
+    }
+
+    /// Bonsai's counterpart of resolving children by hash or by id: a second commit mixes
+    /// nodes loaded back from the db with nodes created in memory, splitting committed
+    /// edges at the root and above the leaves, overwriting and deleting committed leaves.
+    /// The children must resolve to the right nodes both in that commit and once the db
+    /// is reopened
+    #[test]
+    fn updates_over_committed_nodes_resolve_their_children() {
+        let first = [(0b_0000, 1), (0b_1000, 2), (0b_1100, 3)];
+        let mut top = [0u8; 32];
+        top[0] = 0x04;
+        let top = FieldElement::from_bytes_be(&top).unwrap();
+        let second = [
+            (top, felt(4)),
+            (felt(0b_0001), felt(5)),
+            (felt(0b_1000), felt(20)),
+            (felt(0b_1100), FieldElement::ZERO),
+        ];
+
+        let mut storage: HashMap<_, _> = first
+            .iter()
+            .map(|&(key, value)| (felt(key), felt(value)))
+            .collect();
+        let tempdir = tempdir().unwrap();
+        let root = {
+            let db = create_rocks_db(tempdir.path()).unwrap();
+            let mut bonsai_storage: BonsaiStorage<_, _, Pedersen> = BonsaiStorage::new(
+                RocksDB::new(&db, RocksDBConfig::default()),
+                BonsaiStorageConfig::default(),
+            )
+            .unwrap();
+            let mut id_builder = BasicIdBuilder::new();
+
+            for (key, value) in &storage {
+                bonsai_storage
+                    .insert(IDENTIFIER, &trie_key(key), &to_felt(value))
+                    .unwrap();
+            }
+            bonsai_storage.commit(id_builder.new_id()).unwrap();
+            assert_eq!(
+                bonsai_storage.root_hash(IDENTIFIER).unwrap(),
+                reference::root(&storage)
+            );
+
+            for (key, value) in second {
+                bonsai_storage
+                    .insert(IDENTIFIER, &trie_key(&key), &to_felt(&value))
+                    .unwrap();
+                storage.insert(key, value);
+            }
+            bonsai_storage.commit(id_builder.new_id()).unwrap();
+            let root = bonsai_storage.root_hash(IDENTIFIER).unwrap();
+            assert_eq!(root, reference::root(&storage));
+            root
+        };
+        assert_eq!(
+            bonsai_root(&storage, &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap(),
+            root
+        );
+
+        // Every node now comes from the db
+        let db = create_rocks_db(tempdir.path()).unwrap();
+        let mut bonsai_storage: BonsaiStorage<BasicId, _, Pedersen> = BonsaiStorage::new(
+            RocksDB::new(&db, RocksDBConfig::default()),
+            BonsaiStorageConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(bonsai_storage.root_hash(IDENTIFIER).unwrap(), root);
+        bonsai_storage
+            .insert(IDENTIFIER, &trie_key(&felt(0b_0100)), &Felt::from(6u64))
+            .unwrap();
+        storage.insert(felt(0b_0100), felt(6));
+        // Past the ids of the two commits before the reopening
+        let mut id_builder = BasicIdBuilder::new();
+        let _ = (id_builder.new_id(), id_builder.new_id());
+        bonsai_storage.commit(id_builder.new_id()).unwrap();
+        assert_eq!(
+            bonsai_storage.root_hash(IDENTIFIER).unwrap(),
+            reference::root(&storage)
+        );
+    }
+
+    /// Tries built concurrently on several threads, each on its own db, give the roots
+    /// of a trie built alone on one thread
+    #[test]
+    fn roots_do_not_depend_on_the_thread_count() {
+        const THREADS: usize = 4;
+        for size in [1u64, 64, 2_000] {
+            let storage: Vec<_> = (0..size)
+                .map(|key| (felt(key * 7919 + 1), felt(key % 97)))
+                .collect();
+            let roots = || {
+                let storage = || storage.iter().map(|(key, value)| (key, value));
+                (
+                    bonsai_root(storage(), &ProgressBar::hidden(), &BonsaiOptions::default())
+                        .unwrap(),
+                    reference::root(storage()),
+                )
+            };
+
+            let alone = roots();
+            assert_eq!(alone.0, alone.1, "{size} keys");
+            std::thread::scope(|scope| {
+                let threads: Vec<_> = (0..THREADS).map(|_| scope.spawn(roots)).collect();
+                for thread in threads {
+                    assert_eq!(thread.join().unwrap(), alone, "{size} keys");
+                }
+            });
+        }
+    }
+
+    /// End to end against a starknet-devnet, e.g. `starknet-devnet --seed 0`, listening on
+    /// `DEVNET_URL` or `http://127.0.0.1:5050`. Each mint is a block writing known balances
+    /// to the fee token's storage. The root accumulated from devnet's state diffs must be
+    /// the same in both engines, and equal the root of the absolute values devnet reports
+    /// for the same keys, as devnet exposes no storage root of its own
+    #[cfg(feature = "devnet-tests")]
+    #[tokio::test]
+    async fn devnet_storage_roots_match() {
+        use starknet::providers::jsonrpc::{HttpTransport, JsonRpcClient};
+        use starknet::providers::Provider;
+
+        const FEE_TOKEN: &str = "0x49d36570d4e46f48e99674bd3fcc84644ddd6b96f7c741b1562b82f9e004dc7";
+        let url =
+            std::env::var("DEVNET_URL").unwrap_or_else(|_| "http://127.0.0.1:5050".to_string());
+        let rpc_url: Url = format!("{url}/rpc").parse().unwrap();
+        let fee_token = FieldElement::from_hex_be(FEE_TOKEN).unwrap();
+
+        let client = reqwest::Client::new();
+        for (address, amount) in [(0x111u64, 1_000u64), (0x222, 2_000), (0x111, 500)] {
+            let body = serde_json::json!({
+                "address": format!("{address:#x}"),
+                "amount": amount,
+                "unit": "WEI",
+            });
+            client
+                .post(format!("{url}/mint"))
+                .header("content-type", "application/json")
+                .body(body.to_string())
+                .send()
+                .await
+                .unwrap()
+                .error_for_status()
+                .unwrap();
+        }
+
+        let latest = JsonRpcClient::new(HttpTransport::new(rpc_url.clone()))
+            .block_number()
+            .await
+            .unwrap();
+        let mut accumulated = HashMap::new();
+        for block in 0..=latest {
+            accumulated.extend(rpc::storage_diff(&rpc_url, fee_token, block).await.unwrap());
+        }
+        assert!(!accumulated.is_empty(), "the mints wrote no storage");
+
+        let keys: Vec<_> = accumulated.keys().copied().collect();
+        let reported: HashMap<_, _> =
+            rpc::storage_at(&rpc_url, fee_token, &keys, latest, &ProgressBar::hidden())
+                .await
+                .unwrap()
+                .into_iter()
+                .collect();
+        assert_eq!(accumulated, reported);
+
+        let bonsai = bonsai_root(
+            &accumulated,
+            &ProgressBar::hidden(),
+            &BonsaiOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(bonsai, reference::root(&accumulated));
+        assert_eq!(bonsai, reference::root(&reported));
+        assert_ne!(bonsai, Felt::ZERO);
+    }
+}
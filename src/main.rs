@@ -1,48 +1,158 @@
-use std::collections::HashMap;
-use std::time::Duration;
-
-use anyhow::anyhow;
-use bitvec::view::BitView;
-use bonsai_trie::databases::{create_rocks_db, RocksDBConfig};
-use bonsai_trie::id::BasicIdBuilder;
-use bonsai_trie::BonsaiStorageConfig;
-use bonsai_trie::{databases::RocksDB, BonsaiStorage};
-use indicatif::{ProgressBar, ProgressStyle};
-use lazy_static::lazy_static;
-use starknet::core::types::FieldElement;
-use starknet::providers::{
-    sequencer::models::{
-        state_update::StorageDiff,
-        BlockId::{self},
-        StateUpdate,
-    },
-    SequencerGatewayProvider,
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bonsai_trie::databases::{create_rocks_db, RocksDB, RocksDBConfig};
+use deoxys_test::{
+    accumulate_storage, adversarial, apply_state_update,
+    attestation::{Attestation, ResultsDigest},
+    audit::Report,
+    block_at_timestamp, block_label, blocks_touching_contract, bonsai_class_root, bonsai_root,
+    check_felt_compat, class_trie_leaf, contract_leaf_hash, current_root, declared_classes,
+    deployed_contract, deployments, get_state_update, golden, http_client, layout,
+    metrics::{self, Metrics},
+    network_provider, offset_key, presliced_key, print_result, process_range, reference,
+    reference_root, results, rocks_db_error, rpc, save_storage_update, selected_storage,
+    session::{BonsaiSession, StorageTrie},
+    snapshot, storage_digest, storage_root, to_felt, to_field_element, validate_identifier,
+    BlockHook, BlockResult, BonsaiOptions, FetchConfig, KeyFilter, LeafTransform, RateLimiter,
+    RootFormat, RunConfig, StorageTuning, TimeUnit, CONTRACT_DATA, CONTRACT_STORAGE, IDENTIFIER,
 };
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use starknet::core::types::FieldElement;
+use starknet::providers::sequencer::models::state_update::StorageDiff;
+use starknet::providers::sequencer::models::BlockId;
+use starknet::providers::SequencerGatewayProvider;
 use starknet_types_core::felt::Felt;
-use starknet_types_core::hash::Pedersen;
 use tempfile::tempdir;
-use tokio::sync::RwLock;
+use url::Url;
+
+use crate::cli::{Args, Command, ProgressTo};
 
-lazy_static! {
-    static ref CONTRACT_STORAGE: RwLock<HashMap<FieldElement, RwLock<HashMap<FieldElement, FieldElement>>>> =
-        RwLock::new(HashMap::new());
+mod cli;
+
+fn main() {
+    let args = Args::parse_resolved();
+    let started = Instant::now();
+
+    // A single thread leaves no task scheduling to chance
+    let mut runtime = if args.deterministic {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        tokio::runtime::Builder::new_multi_thread()
+    };
+    runtime
+        .enable_all()
+        .build()
+        .expect("Failed to start the Tokio runtime")
+        .block_on(run(args, started));
 }
 
-const IDENTIFIER: &[u8; 10] = b"0xcontract";
+async fn run(args: Args, started: Instant) {
+    validate_identifier(IDENTIFIER).expect("Invalid Bonsai identifier");
+    check_felt_compat().expect("starknet-rs and Bonsai felts are incompatible");
 
-#[tokio::main]
-async fn main() {
-    let provider = SequencerGatewayProvider::starknet_alpha_mainnet();
+    match &args.command {
+        Some(Command::CompareStdin { keys_presliced }) => {
+            return compare_stdin(*keys_presliced, args.root_format)
+        }
+        Some(Command::AdversarialCheck) => {
+            return adversarial_check(args.leaf_transform, args.root_format)
+        }
+        Some(Command::CommitCheck { snapshot }) => {
+            return commit_check(snapshot.as_deref(), args.root_format)
+        }
+        Some(Command::ConfigSweep { snapshot }) => {
+            return config_sweep(
+                snapshot,
+                args.root_format,
+                args.time_unit,
+                args.time_precision.unwrap_or(2),
+            )
+        }
+        Some(Command::IncrementalBench { blocks, keys, seed }) => {
+            return incremental_bench(
+                *blocks,
+                *keys,
+                *seed,
+                args.root_format,
+                args.time_unit,
+                args.time_precision.unwrap_or(2),
+            )
+        }
+        Some(Command::ConcurrencyCheck) => return concurrency_check(args.root_format).await,
+        Some(Command::OrderCheck { snapshot, seed }) => {
+            return order_check(snapshot, *seed, args.root_format)
+        }
+        Some(Command::OffsetProbe { snapshot, offset }) => {
+            return offset_probe(snapshot, *offset as usize, args.root_format)
+        }
+        Some(Command::EndiannessProbe { snapshot }) => {
+            return endianness_probe(snapshot, args.root_format)
+        }
+        Some(Command::ValidateMap { map: Some(path) }) => {
+            let file = File::open(path).expect("Failed to open storage map");
+            let storage_diffs: Vec<StorageDiff> = serde_json::from_reader(io::BufReader::new(file))
+                .expect("Failed to parse storage map");
+            let storage = storage_diffs
+                .into_iter()
+                .map(|StorageDiff { key, value }| (key, value))
+                .collect();
+            validate_map(&storage, args.root_format);
+            return;
+        }
+        Some(Command::RootFromSnapshot { snapshot }) => {
+            return root_from_snapshot(snapshot, args.root_format)
+        }
+        Some(Command::Diff { left, right }) => return diff_results(left, right, args.root_format),
+        #[cfg(feature = "node-export")]
+        Some(Command::ExportNodes {
+            snapshot,
+            out,
+            max_nodes,
+        }) => return export_nodes(snapshot, out, *max_nodes, args.root_format),
+        _ => {}
+    }
 
-    // Change this to update the range of blocks to test
-    // NOTE: This should contain the block at which `contract_address` was defined
-    let block_range = 0..400;
+    let client = http_client(Duration::from_millis(args.request_timeout_ms)).unwrap();
+    let provider = network_provider(args.network, client.clone()).unwrap();
+    let mut block_range = args.from..args.to;
+    let contract_address = match args.contract_from_tx {
+        Some(transaction_hash) => deployed_contract(&provider, transaction_hash)
+            .await
+            .unwrap(),
+        None => args.contract,
+    };
 
-    // The contract to watch
-    let contract_address = FieldElement::from_hex_be(
-        "0x020cfa74ee3564b4cd5435cdace0f9c4d43b939620e4a0bb5076105df0a626c6",
-    )
-    .unwrap();
+    if let Some(from_time) = args.from_time {
+        block_range.start = block_at_timestamp(&provider, from_time).await.unwrap();
+    }
+    if let Some(to_time) = args.to_time {
+        block_range.end = block_at_timestamp(&provider, to_time).await.unwrap();
+    }
+    if args.from_time.is_some() || args.to_time.is_some() {
+        println!(
+            "🕰️ timestamps resolved to blocks {}..{}",
+            block_range.start, block_range.end
+        );
+    }
+    // Every run covers at least one block, its last one being `block_range.end - 1`
+    if block_range.end <= block_range.start {
+        eprintln!(
+            "empty block range {}..{}, --to must be above --from",
+            block_range.start, block_range.end
+        );
+        process::exit(2);
+    }
 
     // ohhh... pretty 👀
     let bar = ProgressBar::new(block_range.end - block_range.start);
@@ -51,87 +161,2715 @@ async fn main() {
             .template("[{elapsed_precise}] {wide_bar:.cyan/blue} {pos:>7}/{len:7} {msg}")
             .unwrap(),
     );
-    bar.println(format!("📜 checking for contract {contract_address:#x}"));
+    bar.set_draw_target(match args.progress_to {
+        ProgressTo::Stdout => ProgressDrawTarget::stdout(),
+        ProgressTo::Stderr => ProgressDrawTarget::stderr(),
+        ProgressTo::None => ProgressDrawTarget::hidden(),
+    });
+    let label = if args.label.is_empty() {
+        String::new()
+    } else {
+        format!("[{}] ", args.label)
+    };
+    print_result(
+        &bar,
+        format!("{label}📜 checking for contract {contract_address:#x}"),
+    );
 
-    for i in block_range {
-        bar.inc(1);
+    if let Some(path) = &args.load_snapshot {
+        let snapshot = snapshot::read(path).unwrap();
+        assert_eq!(
+            snapshot.contract, contract_address,
+            "Snapshot is for another contract"
+        );
+        snapshot::restore(&snapshot).await;
+
+        let restored_root = current_root(contract_address, &RunConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            restored_root,
+            to_felt(&snapshot.root),
+            "Restored storage does not hash to the snapshot's root"
+        );
+        print_result(
+            &bar,
+            format!(
+                "📥 loaded snapshot at block {} with {} keys, root {} verified",
+                snapshot.block,
+                snapshot.storage.len(),
+                args.root_format.format(&restored_root)
+            ),
+        );
+
+        // Replaying blocks already in the snapshot would bring back stale values
+        let resume = snapshot.block + 1;
+        if block_range.start < resume {
+            block_range.start = resume;
+            bar.set_length(block_range.end.saturating_sub(resume));
+            print_result(
+                &bar,
+                format!("⏩ resuming after the snapshot, at block {resume}"),
+            );
+        } else if block_range.start > resume {
+            print_result(
+                &bar,
+                format!(
+                    "⚠️ blocks {resume}..{} between the snapshot and --from are not applied",
+                    block_range.start
+                ),
+            );
+        }
+    }
+
+    let metrics = match args.metrics_addr {
+        Some(addr) => {
+            let metrics = Arc::new(Metrics::default());
+            metrics::serve(addr, metrics.clone()).await.unwrap();
+            print_result(&bar, format!("📈 serving metrics on http://{addr}/metrics"));
+            Some(metrics)
+        }
+        None => None,
+    };
+
+    let parameters = serde_json::json!({
+        "contract": format!("{contract_address:#x}"),
+        "from": block_range.start,
+        "to": block_range.end,
+        "label": args.label,
+        "snapshot": args.load_snapshot.as_ref().map(|path| path.display().to_string()),
+        "key_prefix": args.key_prefix,
+        "volatile_keys": args.volatile_key.iter().map(|key| format!("{key:#x}")).collect::<Vec<_>>(),
+        "leaf_transform": format!("{:?}", args.leaf_transform),
+        "reference_check": args.reference_check,
+        "expect_root": args.expect_root.map(|root| format!("{root:#x}")),
+        "golden": args.golden.as_ref().map(|path| path.display().to_string()),
+    });
+
+    let timeline = matches!(
+        args.command,
+        Some(Command::Timeline { .. } | Command::Audit { .. })
+    );
+    let config = RunConfig {
+        fetch: FetchConfig {
+            feeder_gateway_url: args.network.feeder_gateway().to_string(),
+            client,
+            save_raw_on_error: args.save_raw_on_error,
+            rate_limiter: args.rps.map(RateLimiter::new),
+            bar: bar.clone(),
+            ..Default::default()
+        },
+        filter: KeyFilter {
+            prefix: args.key_prefix,
+            volatile: args.volatile_key.into_iter().collect(),
+        },
+        leaf_transform: args.leaf_transform,
+        root_format: args.root_format,
+        // The timeline and audit record both engines' roots, mismatches included
+        reference_check: args.reference_check || timeline,
+        max_keys_per_block: args.max_keys_per_block,
+        max_keys_error: args.max_keys_error,
+        skip_noop: args.skip_noop,
+        root_delta: args.root_delta,
+        bonsai: BonsaiOptions {
+            max_key_logs: args.max_key_logs,
+            check_uncommitted: args.check_uncommitted,
+            ..Default::default()
+        },
+        summary_only: args.summary_only,
+        log_clears: args.log_clears,
+        metrics,
+        storage_only: args.storage_only,
+        no_assert: args.no_assert || timeline,
+        // A deadline too far to represent is never reached
+        deadline: args
+            .deadline
+            .and_then(|deadline| started.checked_add(deadline)),
+        strict_keys: args.strict_keys,
+        deterministic: args.deterministic,
+        layout: args
+            .layout
+            .as_deref()
+            .map(|path| layout::read(path).unwrap()),
+    };
+
+    if let Some(Command::ValidateMap { map: None }) = args.command {
+        let (storage, _) = accumulate_storage(
+            &provider,
+            contract_address,
+            block_range,
+            &bar,
+            &config.fetch,
+        )
+        .await
+        .unwrap();
+        bar.finish_and_clear();
+        validate_map(&storage, config.root_format);
+        return;
+    }
+
+    if let Some(Command::LeafHash { block }) = args.command {
+        assert!(
+            !config.storage_only,
+            "leaf-hash needs the class hash and nonce, which --storage-only does not accumulate"
+        );
+        require_block_in_range("leaf-hash", block, &block_range);
+        bar.set_length(block + 1 - block_range.start);
+        process_range(
+            &provider,
+            contract_address,
+            block_range.start..block + 1,
+            &bar,
+            &config,
+            None,
+        )
+        .await
+        .unwrap_or_else(|e| exit_with_error(&bar, e));
+        bar.finish_and_clear();
+
+        let contract_data = CONTRACT_DATA
+            .read()
+            .await
+            .get(&contract_address)
+            .copied()
+            .expect("Contract was not deployed in the range, its class hash is unknown");
+        let storage_root = current_root(contract_address, &config).await.unwrap();
+        let class_hash = to_felt(&contract_data.class_hash);
+        let nonce = to_felt(&contract_data.nonce);
+        let leaf_hash = contract_leaf_hash(&class_hash, &storage_root, &nonce);
+
+        let format = config.root_format;
+        println!("📜 contract {contract_address:#x} at block {block}");
+        println!("🏷️ class hash:   {}", format.format(&class_hash));
+        println!("🌳 storage root: {}", format.format(&storage_root));
+        println!("🔢 nonce:        {}", format.format(&nonce));
+        println!("🍃 leaf hash:    {}", format.format(&leaf_hash));
+        return;
+    }
+
+    if let Some(Command::Twins { other }) = args.command {
+        twins(
+            &provider,
+            [contract_address, other],
+            block_range,
+            &bar,
+            &config,
+        )
+        .await;
+        return;
+    }
+
+    if args.list_only {
+        let blocks = blocks_touching_contract(
+            &provider,
+            contract_address,
+            block_range.clone(),
+            &bar,
+            &config.fetch,
+        )
+        .await
+        .unwrap();
+        bar.finish_and_clear();
+
+        for block in &blocks {
+            println!("{block}");
+        }
+        if blocks.is_empty() {
+            warn_no_activity(contract_address, &block_range);
+        }
+        return;
+    }
+
+    if let Some(Command::ListDeploys { json }) = args.command {
+        let deployments = deployments(&provider, block_range, &bar, &config.fetch)
+            .await
+            .unwrap();
+        bar.finish_and_clear();
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&deployments).unwrap());
+        } else {
+            println!("{:<12} {:<66} {:<66}", "block", "contract", "class hash");
+            for deployment in &deployments {
+                println!(
+                    "{:<12} {:<66} {:<66}",
+                    deployment.block,
+                    config.root_format.format(&to_felt(&deployment.address)),
+                    config.root_format.format(&to_felt(&deployment.class_hash))
+                );
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Timeline { csv, json }) = &args.command {
+        let mut rows = Vec::new();
+        let mut on_block = |result: &BlockResult| {
+            let reference = result.reference_root.unwrap();
+            // Both come from the same block, anything else is a bug of the timeline itself
+            result.root.matches(&reference).unwrap();
+            rows.push((
+                result.block,
+                result.written,
+                result.root.root,
+                reference.root,
+            ));
+        };
+        process_range(
+            &provider,
+            contract_address,
+            block_range.clone(),
+            &bar,
+            &config,
+            Some(&mut on_block as BlockHook),
+        )
+        .await
+        .unwrap_or_else(|e| exit_with_error(&bar, e));
+        bar.finish_and_clear();
+        write_timeline(&rows, csv.as_deref(), json.as_deref(), config.root_format);
+
+        if rows.is_empty() {
+            warn_no_activity(contract_address, &block_range);
+        }
+        let mismatches = rows
+            .iter()
+            .filter(|(_, _, bonsai, reference)| bonsai != reference)
+            .count();
+        if mismatches > 0 {
+            println!(
+                "❌ the engines disagree at {mismatches} of {} blocks",
+                rows.len()
+            );
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Command::Audit { markdown }) = &args.command {
+        audit(
+            &provider,
+            contract_address,
+            block_range,
+            markdown.as_deref(),
+            &bar,
+            &config,
+        )
+        .await;
+        return;
+    }
+
+    if let Some(Command::RootDelta {
+        from_block,
+        to_block,
+    }) = args.command
+    {
+        root_delta(
+            &provider,
+            contract_address,
+            block_range.start,
+            [from_block, to_block],
+            &bar,
+            &config,
+        )
+        .await;
+        return;
+    }
+
+    if let Some(Command::Union { members }) = &args.command {
+        union(&provider, members, block_range, &bar, &config).await;
+        return;
+    }
+
+    if let Some(Command::ClassTrie) = args.command {
+        class_trie(&provider, block_range, &bar, &config, args.expect_root).await;
+        return;
+    }
+
+    if let Some(Command::DeployCheck) = args.command {
+        deploy_check(&provider, contract_address, block_range, &bar, &config).await;
+        return;
+    }
+
+    if let Some(Command::Bisect) = args.command {
+        bisect(&provider, contract_address, block_range, &bar, &config).await;
+        return;
+    }
+
+    if let Some(Command::Reorg {
+        revert_to,
+        alternate,
+    }) = &args.command
+    {
+        reorg(
+            &provider,
+            contract_address,
+            block_range,
+            *revert_to,
+            alternate,
+            &bar,
+            &config,
+        )
+        .await;
+        return;
+    }
+
+    if let Some(Command::ReverseCheck) = args.command {
+        reverse_check(&provider, contract_address, block_range, &bar, &config).await;
+        return;
+    }
+
+    if let Some(Command::LatestDelta) = args.command {
+        latest_delta(&provider, contract_address, block_range, &bar, &config).await;
+        return;
+    }
+
+    if let Some(Command::ZeroSemantics { block }) = args.command {
+        require_block_in_range("zero-semantics", block, &block_range);
+        bar.set_length(block + 1 - block_range.start);
+        process_range(
+            &provider,
+            contract_address,
+            block_range.start..block + 1,
+            &bar,
+            &config,
+            None,
+        )
+        .await
+        .unwrap_or_else(|e| exit_with_error(&bar, e));
+        bar.finish_and_clear();
+        zero_semantics(contract_address, block, args.expect_root, &config).await;
+        return;
+    }
+
+    if let Some(Command::StorageAt {
+        block,
+        rpc_url,
+        keys_file,
+    }) = &args.command
+    {
+        require_block_in_range("storage-at", *block, &block_range);
+        bar.set_length(block + 1 - block_range.start);
+        process_range(
+            &provider,
+            contract_address,
+            block_range.start..block + 1,
+            &bar,
+            &config,
+            None,
+        )
+        .await
+        .unwrap_or_else(|e| exit_with_error(&bar, e));
+        storage_at(
+            contract_address,
+            *block,
+            rpc_url,
+            keys_file.as_deref(),
+            &bar,
+            &config,
+        )
+        .await;
+        return;
+    }
+
+    if let Some(Command::CrossProvider { rpc_url }) = &args.command {
+        cross_provider(
+            &provider,
+            contract_address,
+            block_range,
+            rpc_url,
+            &bar,
+            &config,
+        )
+        .await;
+        return;
+    }
+
+    if args.verify_rebuild_final || args.commit_every.is_some() {
+        live_trie(
+            &provider,
+            contract_address,
+            block_range,
+            args.commit_every,
+            args.verify_rebuild_final,
+            &bar,
+            &config,
+        )
+        .await;
+        return;
+    }
+
+    if let Some(samples) = args.sample_blocks {
+        assert!(
+            samples > 0,
+            "--sample-blocks must sample at least one block"
+        );
+        let blocks = blocks_touching_contract(
+            &provider,
+            contract_address,
+            block_range.clone(),
+            &bar,
+            &config.fetch,
+        )
+        .await
+        .unwrap();
+        if blocks.is_empty() {
+            warn_no_activity(contract_address, &block_range);
+            return;
+        }
+
+        let sampled: Vec<u64> = blocks
+            .choose_multiple(&mut StdRng::seed_from_u64(args.seed), samples)
+            .copied()
+            .collect();
+        print_result(
+            &bar,
+            format!(
+                "🎲 sampled {} of the {} blocks touching the contract",
+                sampled.len(),
+                blocks.len()
+            ),
+        );
+        bar.reset();
+        checkpoints(
+            &provider,
+            contract_address,
+            block_range.start,
+            sampled,
+            &bar,
+            &config,
+        )
+        .await;
+        return;
+    }
+
+    if !args.checkpoints.is_empty() {
+        checkpoints(
+            &provider,
+            contract_address,
+            block_range.start,
+            args.checkpoints,
+            &bar,
+            &config,
+        )
+        .await;
+        return;
+    }
+
+    if let Some(block_hash) = args.block_hash {
+        let block = BlockId::Hash(block_hash);
+        let state_update = get_state_update(&provider, block, &config.fetch)
+            .await
+            .unwrap();
 
-        let state_update = get_state_update(&provider, i).await.unwrap();
-        if let Some(storage_updates) = state_update.state_diff.storage_diffs.get(&contract_address)
-        {
-            bar.println(format!("🧱 block {i}"));
-            save_storage_update(contract_address, storage_updates).await;
+        match state_update.state_diff.storage_diffs.get(&contract_address) {
+            Some(storage_updates) => {
+                print_result(&bar, format!("🧱 block {}", block_label(block)));
+                save_storage_update(contract_address, storage_updates).await;
 
-            let storage_root = storage_root(contract_address, &bar).await;
-            bar.println(format!("🌳 storage root: {storage_root:#064x}"));
+                let (storage_root, _) = storage_root(contract_address, &bar, true, &config)
+                    .await
+                    .unwrap();
+                print_result(
+                    &bar,
+                    format!(
+                        "🌳 storage root: {}",
+                        config.root_format.format(&storage_root)
+                    ),
+                );
+            }
+            None => print_result(
+                &bar,
+                format!(
+                    "🧱 block {} does not touch the contract",
+                    block_label(block)
+                ),
+            ),
         }
+
+        bar.finish_and_clear();
+        return;
+    }
+
+    let last_block = block_range.end - 1;
+    let expect_block = args.expect_root_block.unwrap_or(last_block);
+    let mut expect_observed = None;
+    let mut computed = golden::Roots::new();
+    let mut timings = args.root_timing_csv.as_ref().map(|path| {
+        let mut csv = BufWriter::new(File::create(path).expect("Failed to create timing CSV"));
+        let unit = match args.time_unit {
+            TimeUnit::Auto | TimeUnit::S => "seconds",
+            TimeUnit::Ms => "ms",
+            TimeUnit::Us => "us",
+        };
+        writeln!(csv, "block,keys,hash_{unit}").unwrap();
+        csv
+    });
+    let mut jsonl = args
+        .jsonl_output
+        .as_ref()
+        .map(|path| File::create(path).expect("Failed to create JSON lines output"));
+    let mut root_chain = args
+        .root_chain
+        .as_ref()
+        .map(|path| File::create(path).expect("Failed to create root chain"));
+    let mut index = 0u64;
+    let mut parent_block = None;
+    let mut digest = ResultsDigest::default();
+    let reference_check = config.reference_check;
+    let mut on_block = |result: &BlockResult| {
+        digest.update(result);
+        if let Some(root_chain) = root_chain.as_mut() {
+            let line = serde_json::json!({
+                "block": result.block,
+                "root": format!("{:#x}", result.root.root),
+                "parent_block": parent_block,
+                "parent_root": format!("{:#x}", result.previous_root),
+                "index": index,
+            });
+            writeln!(root_chain, "{line}").unwrap();
+            index += 1;
+            parent_block = Some(result.block);
+        }
+        if let Some(jsonl) = jsonl.as_mut() {
+            // Unbuffered, so that every line can be tailed as soon as it is written
+            let line = serde_json::json!({
+                "block": result.block,
+                "contract": format!("{:#x}", result.contract),
+                "root": format!("{:#x}", result.root.root),
+                "previous_root": format!("{:#x}", result.previous_root),
+                "keys": result.keys,
+                "match": reference_check.then_some(!result.mismatch),
+            });
+            writeln!(jsonl, "{line}").unwrap();
+        }
+        if let Some(csv) = timings.as_mut() {
+            let hash_time = args.time_unit.value(result.hash_time);
+            match args.time_precision {
+                Some(precision) => writeln!(
+                    csv,
+                    "{},{},{hash_time:.precision$}",
+                    result.block, result.keys
+                ),
+                None => writeln!(csv, "{},{},{hash_time}", result.block, result.keys),
+            }
+            .unwrap();
+        }
+        if result.block <= expect_block {
+            expect_observed = Some((
+                result.block,
+                result.root.root,
+                result.reference_root.map(|reference| reference.root),
+            ));
+        }
+        computed.insert(result.block, to_field_element(&result.root.root));
+    };
+
+    let start = Instant::now();
+    let stats = process_range(
+        &provider,
+        contract_address,
+        block_range.clone(),
+        &bar,
+        &config,
+        Some(&mut on_block as BlockHook),
+    )
+    .await
+    .unwrap_or_else(|e| exit_with_error(&bar, e));
+    let elapsed = start.elapsed();
+    if let Some(mut csv) = timings {
+        csv.flush().unwrap();
     }
 
     bar.finish();
+
+    // Only what was covered before the deadline is exported
+    match (&args.export_snapshot, stats.last_applied) {
+        (Some(path), Some(last_applied)) => {
+            let snapshot = snapshot::export(contract_address, last_applied, path)
+                .await
+                .unwrap();
+            println!(
+                "📤 exported {} keys at block {last_applied} to {}, root {}",
+                snapshot.storage.len(),
+                path.display(),
+                args.root_format.format(&to_felt(&snapshot.root))
+            );
+        }
+        (Some(_), None) => {
+            println!("⚠️ no block was applied before the deadline, no snapshot exported")
+        }
+        (None, _) => {}
+    }
+
+    println!(
+        "{label}📊 {} blocks processed, {} with diffs, {} without any storage diff, {} keys inserted, {} cleared in {} ({:.2} blocks/s)",
+        stats.blocks,
+        stats.blocks_with_diffs,
+        stats.empty_blocks,
+        stats.keys_inserted,
+        stats.keys_cleared,
+        args.time_unit.format(elapsed, args.time_precision.unwrap_or(2)),
+        stats.blocks as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "{label}🧮 storage digest: {}",
+        config
+            .root_format
+            .format(&storage_digest(contract_address).await)
+    );
+    if let Some(stopped_at) = stats.stopped_at {
+        println!(
+            "{label}⏰ deadline reached, stopped before block {stopped_at} of {}..{}",
+            block_range.start, block_range.end
+        );
+        if args.export_snapshot.is_some() {
+            println!(
+                "⏩ resume with --load-snapshot and --to {}",
+                block_range.end
+            );
+        }
+    }
+    if stats.blocks_with_diffs == 0 {
+        warn_no_activity(contract_address, &block_range);
+    }
+
+    if let Some(path) = &args.write_golden {
+        golden::write(path, &computed).unwrap();
+        println!("🥇 wrote {} roots to {}", computed.len(), path.display());
+    }
+
+    let mut failed = false;
+    if stats.mismatches > 0 {
+        println!(
+            "❌ {} blocks had diverging Bonsai and reference roots",
+            stats.mismatches
+        );
+        failed = true;
+    }
+    if let Some(path) = &args.golden {
+        failed |= !check_golden(&golden::read(path).unwrap(), &computed, config.root_format);
+    }
+
+    if let Some(expected) = args.expect_root {
+        let expected = to_felt(&expected);
+
+        match expect_observed {
+            None => {
+                println!("❌ no root was computed at or before block {expect_block}");
+                failed = true;
+            }
+            Some((block, root, reference_root)) => {
+                println!(
+                    "🎯 expected root at block {expect_block}: {}",
+                    config.root_format.format(&expected)
+                );
+                println!(
+                    "🌳 bonsai root (last changed at block {block}): {}",
+                    config.root_format.format(&root)
+                );
+                if let Some(reference_root) = reference_root {
+                    println!(
+                        "🌳 reference root (at block {block}): {}",
+                        config.root_format.format(&reference_root)
+                    );
+                }
+
+                if root != expected {
+                    println!("❌ computed root differs from the expected root");
+                    failed = true;
+                } else {
+                    println!("✅ computed root matches the expected root");
+                }
+            }
+        }
+    }
+
+    if let Some(path) = &args.attestation {
+        Attestation::new(parameters, stats.blocks, digest, !failed)
+            .write(path, args.attestation_key.as_deref())
+            .unwrap();
+        let signed = if args.attestation_key.is_some() {
+            "signed "
+        } else {
+            ""
+        };
+        println!("🔏 wrote {signed}attestation to {}", path.display());
+    }
+
+    if failed {
+        process::exit(1);
+    }
+    if stats.stopped_at.is_some() {
+        process::exit(3);
+    }
 }
 
-async fn get_state_update(
-    provider: &SequencerGatewayProvider,
-    i: u64,
-) -> anyhow::Result<StateUpdate> {
-    let mut retries = 15;
+/// Reports drift of `computed` roots from `golden`, returning whether there is none.
+fn check_golden(golden: &golden::Roots, computed: &golden::Roots, root_format: RootFormat) -> bool {
+    let mut mismatches = 0;
+    let mut checked = 0;
 
-    while retries > 0 {
-        if let Ok(state_update) = provider.get_state_update(BlockId::Number(i)).await {
-            return Ok(state_update);
+    for (block, root) in computed {
+        let Some(expected) = golden.get(block) else {
+            continue;
+        };
+        checked += 1;
+
+        if root != expected {
+            mismatches += 1;
+            println!(
+                "🥇 golden mismatch at block {block}: expected {}, computed {}",
+                root_format.format(&to_felt(expected)),
+                root_format.format(&to_felt(root))
+            );
         }
+    }
+
+    let unchecked = golden
+        .keys()
+        .filter(|block| !computed.contains_key(block))
+        .count();
+    if unchecked > 0 {
+        println!("⚠️ {unchecked} golden blocks had no computed root in this run");
+    }
+
+    if mismatches > 0 {
+        println!("❌ {mismatches} of {checked} roots drifted from the golden file");
+        return false;
+    }
+    println!("✅ {checked} roots match the golden file");
+    true
+}
+
+#[derive(Deserialize)]
+struct PreslicedDiff {
+    key: String,
+    value: FieldElement,
+}
+
+fn compare_stdin(keys_presliced: bool, root_format: RootFormat) {
+    let storage: HashMap<FieldElement, FieldElement> = if keys_presliced {
+        let storage_diffs: Vec<PreslicedDiff> =
+            serde_json::from_reader(io::stdin()).expect("Failed to parse storage diffs from stdin");
+        storage_diffs
+            .into_iter()
+            .map(|PreslicedDiff { key, value }| {
+                let key = presliced_key(&key).unwrap_or_else(|e| {
+                    eprintln!("invalid presliced key on stdin: {e:#}");
+                    process::exit(2);
+                });
+                (key, value)
+            })
+            .collect()
+    } else {
+        let storage_diffs: Vec<StorageDiff> =
+            serde_json::from_reader(io::stdin()).expect("Failed to parse storage diffs from stdin");
+        storage_diffs
+            .into_iter()
+            .map(|StorageDiff { key, value }| (key, value))
+            .collect()
+    };
+
+    let bonsai_root =
+        bonsai_root(&storage, &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap();
+    let reference_root = reference::root(&storage);
+
+    println!("🔑 {} keys", storage.len());
+    println!("🌳 bonsai root:    {}", root_format.format(&bonsai_root));
+    println!("🌳 reference root: {}", root_format.format(&reference_root));
+    if bonsai_root == reference_root {
+        println!("✅ roots match");
+    } else {
+        println!("❌ roots differ");
+    }
+}
+
+/// Prints the Bonsai and reference roots of `storage` and the shape of its trie, exiting
+/// unless they agree, and returns the root.
+fn validate_map(storage: &HashMap<FieldElement, FieldElement>, root_format: RootFormat) -> Felt {
+    let bonsai_root =
+        bonsai_root(storage, &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap();
+    let reference_root = reference::root(storage);
+    let shape = reference::shape(storage);
+    let zeros = storage.len() - shape.leaves;
+
+    println!(
+        "🔑 {} keys, {zeros} of them zero and left out of the trie",
+        storage.len()
+    );
+    println!(
+        "🍃 {} leaves, {} binary and {} edge nodes",
+        shape.leaves, shape.binary_nodes, shape.edge_nodes
+    );
+    println!("🌳 bonsai root:    {}", root_format.format(&bonsai_root));
+    println!("🌳 reference root: {}", root_format.format(&reference_root));
+
+    if shape.leaves == 0 && bonsai_root != Felt::ZERO {
+        println!("❌ the map has no leaves but Bonsai's root is not zero");
+        process::exit(1);
+    }
+    if bonsai_root != reference_root {
+        println!("❌ roots differ");
+        process::exit(1);
+    }
+    println!("✅ roots match");
+    bonsai_root
+}
+
+fn root_from_snapshot(path: &Path, root_format: RootFormat) {
+    let snapshot = snapshot::read_unchecked(path).unwrap();
+    println!(
+        "📥 snapshot of contract {:#x} at block {}",
+        snapshot.contract, snapshot.block
+    );
+    let storage = snapshot
+        .storage
+        .iter()
+        .map(|entry| (entry.key, entry.value))
+        .collect();
+
+    let root = validate_map(&storage, root_format);
+    let recorded = to_felt(&snapshot.root);
+    println!("🎯 recorded root:  {}", root_format.format(&recorded));
+    if root != recorded {
+        println!("❌ the snapshot's storage does not hash to its recorded root");
+        process::exit(1);
+    }
+    println!("✅ and match the recorded root");
+}
 
-        retries -= 1;
-        tokio::time::sleep(Duration::from_secs(5)).await;
+fn diff_results(left_path: &Path, right_path: &Path, root_format: RootFormat) {
+    let left = results::read(left_path).unwrap();
+    let right = results::read(right_path).unwrap();
+    let (left_name, right_name) = (left_path.display(), right_path.display());
+    let format = |root: &FieldElement| root_format.format(&to_felt(root));
+
+    let differences = results::diff(&left, &right);
+    for difference in &differences {
+        match difference {
+            results::Difference::Missing { block, left, .. } => {
+                let (present, absent) = if left.is_some() {
+                    (&left_name, &right_name)
+                } else {
+                    (&right_name, &left_name)
+                };
+                println!("➖ block {block}: only in {present}, not in {absent}");
+            }
+            results::Difference::Changed { block, left, right } => {
+                if let (Some(left), Some(right)) = (left.matched, right.matched) {
+                    if left != right {
+                        let (matched, mismatched) = if left {
+                            (&left_name, &right_name)
+                        } else {
+                            (&right_name, &left_name)
+                        };
+                        println!(
+                            "❌ block {block}: matched in {matched} but mismatched in {mismatched}"
+                        );
+                    }
+                }
+                if left.root != right.root {
+                    println!(
+                        "🔀 block {block}: root {} in {left_name}, {} in {right_name}",
+                        format(&left.root),
+                        format(&right.root)
+                    );
+                }
+            }
+        }
     }
 
-    Err(anyhow!("Failed to retrieve state update for block {i}"))
+    let blocks = left
+        .keys()
+        .chain(right.keys())
+        .collect::<BTreeSet<_>>()
+        .len();
+    if !differences.is_empty() {
+        println!(
+            "❌ {} of {blocks} blocks differ between {left_name} and {right_name}",
+            differences.len()
+        );
+        process::exit(1);
+    }
+    println!("✅ {left_name} and {right_name} agree on all {blocks} blocks");
 }
 
-async fn save_storage_update(contract_address: FieldElement, storage_updates: &[StorageDiff]) {
-    let storage_new: HashMap<FieldElement, FieldElement> = storage_updates
+#[cfg(feature = "node-export")]
+fn export_nodes(path: &Path, out: &Path, max_nodes: usize, root_format: RootFormat) {
+    let snapshot = snapshot::read_unchecked(path).unwrap();
+    let storage: HashMap<_, _> = snapshot
+        .storage
         .iter()
-        .map(|StorageDiff { key, value }| (*key, *value))
+        .map(|entry| (entry.key, entry.value))
         .collect();
 
-    let mut contract_storage = CONTRACT_STORAGE.write().await;
+    let bonsai_root =
+        bonsai_root(&storage, &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap();
+    let nodes = match reference::nodes(&storage, max_nodes) {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            println!("❌ {e}, raise --max-nodes to export it anyway");
+            process::exit(1);
+        }
+    };
+    let reference_root = reference::root(&storage);
+
+    let file = File::create(out).expect("Failed to create nodes file");
+    serde_json::to_writer(
+        BufWriter::new(file),
+        &serde_json::json!({
+            "bonsai_root": format!("{bonsai_root:#x}"),
+            "reference_root": format!("{reference_root:#x}"),
+            "nodes": nodes,
+        }),
+    )
+    .expect("Failed to write nodes");
+
+    println!("📤 {} nodes written to {}", nodes.len(), out.display());
+    println!("🌳 bonsai root:    {}", root_format.format(&bonsai_root));
+    println!("🌳 reference root: {}", root_format.format(&reference_root));
+    if bonsai_root != reference_root {
+        println!("❌ roots differ");
+        process::exit(1);
+    }
+}
+
+fn adversarial_check(leaf_transform: LeafTransform, root_format: RootFormat) {
+    let mut diverged = false;
+
+    println!("{:<20} {:<66} {:<66}", "pattern", "bonsai", "reference");
+    for mut pattern in adversarial::patterns() {
+        for (_, value) in &mut pattern.storage {
+            *value = leaf_transform.apply(*value);
+        }
+        let storage = || pattern.storage.iter().map(|(key, value)| (key, value));
+        let bonsai =
+            bonsai_root(storage(), &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap();
+        let reference = reference::root(storage());
+        let inserts_only = bonsai_root(
+            storage().filter(|(_, value)| **value != FieldElement::ZERO),
+            &ProgressBar::hidden(),
+            &BonsaiOptions::default(),
+        )
+        .unwrap();
+        let has_leaves = pattern
+            .storage
+            .iter()
+            .any(|(_, value)| *value != FieldElement::ZERO);
+
+        let agree =
+            bonsai == reference && bonsai == inserts_only && (has_leaves || bonsai == Felt::ZERO);
+        let verdict = if agree { "✅" } else { "❌" };
+        println!(
+            "{:<20} {:<66} {:<66} {verdict}",
+            pattern.name,
+            root_format.format(&bonsai),
+            root_format.format(&reference)
+        );
+        if !agree {
+            println!("   {} keys: {}", pattern.storage.len(), pattern.intent);
+            diverged = true;
+        }
+    }
+
+    if diverged {
+        println!("❌ some patterns have diverging or wrong roots");
+        process::exit(1);
+    }
+    println!("✅ all roots match");
+}
+
+async fn concurrency_check(root_format: RootFormat) {
+    const CONTRACTS: u64 = 4;
+    const ROUNDS: u64 = 20;
+    let contracts: Vec<FieldElement> = (1..=CONTRACTS).map(FieldElement::from).collect();
+    let value = |contract: u64, round: u64| FieldElement::from(round * CONTRACTS + contract);
+
+    let mut tasks = Vec::new();
+    tasks.push(tokio::spawn(async move {
+        for round in 0..ROUNDS {
+            for contract in 1..=CONTRACTS {
+                let diff = StorageDiff {
+                    key: FieldElement::from(round),
+                    value: value(contract, round),
+                };
+                save_storage_update(FieldElement::from(contract), &[diff]).await;
+            }
+            tokio::task::yield_now().await;
+        }
+    }));
+    for &contract in &contracts {
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..ROUNDS {
+                current_root(contract, &RunConfig::default()).await.unwrap();
+            }
+        }));
+    }
 
-    match contract_storage.get(&contract_address) {
-        Some(storage_old) => {
-            storage_old.write().await.extend(storage_new);
+    let joined = tokio::time::timeout(Duration::from_secs(120), async {
+        for task in tasks {
+            task.await.unwrap();
         }
-        None => {
-            contract_storage.insert(contract_address, RwLock::new(storage_new));
+    })
+    .await;
+    if joined.is_err() {
+        println!("❌ tasks still running after 120s, the storage locks likely deadlocked");
+        process::exit(1);
+    }
+
+    let mut diverged = false;
+    for contract in 1..=CONTRACTS {
+        let expected: HashMap<FieldElement, FieldElement> = (0..ROUNDS)
+            .map(|round| (FieldElement::from(round), value(contract, round)))
+            .collect();
+        let bonsai = current_root(FieldElement::from(contract), &RunConfig::default())
+            .await
+            .unwrap();
+        let reference = reference::root(&expected);
+
+        let verdict = if bonsai == reference { "✅" } else { "❌" };
+        println!(
+            "📜 contract {contract:#x}: {} {verdict}",
+            root_format.format(&bonsai)
+        );
+        diverged |= bonsai != reference;
+    }
+
+    if diverged {
+        println!("❌ some roots are wrong after concurrent updates");
+        process::exit(1);
+    }
+    println!("✅ no deadlock, and all {CONTRACTS} roots match");
+}
+
+fn commit_check(snapshot: Option<&Path>, root_format: RootFormat) {
+    let mut datasets: Vec<(String, Vec<(FieldElement, FieldElement)>)> = adversarial::patterns()
+        .into_iter()
+        .map(|pattern| (pattern.name.to_string(), pattern.storage))
+        .collect();
+    if let Some(path) = snapshot {
+        let snapshot = snapshot::read(path).unwrap();
+        let storage = snapshot
+            .storage
+            .into_iter()
+            .map(|entry| (entry.key, entry.value))
+            .collect();
+        datasets.push((path.display().to_string(), storage));
+    }
+
+    let each_insert = BonsaiOptions {
+        commit_each_insert: true,
+        ..Default::default()
+    };
+    let mut diverged = false;
+
+    println!(
+        "{:<20} {:<66} {:<66} agree",
+        "storage", "commit once", "commit each insert"
+    );
+    for (name, storage) in &datasets {
+        let storage = || storage.iter().map(|(key, value)| (key, value));
+        let once =
+            bonsai_root(storage(), &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap();
+        let each = bonsai_root(storage(), &ProgressBar::hidden(), &each_insert).unwrap();
+        let reference = reference::root(storage());
+
+        let agree = once == each && once == reference;
+        let verdict = if agree { "✅" } else { "❌" };
+        println!(
+            "{name:<20} {:<66} {:<66} {verdict}",
+            root_format.format(&once),
+            root_format.format(&each)
+        );
+        if !agree {
+            println!("   reference root: {}", root_format.format(&reference));
+            diverged = true;
         }
+    }
+
+    if diverged {
+        println!("❌ commit granularity changes some roots");
+        process::exit(1);
+    }
+    println!("✅ both commit strategies give the reference root");
+}
+
+fn config_sweep(path: &Path, root_format: RootFormat, time_unit: TimeUnit, precision: usize) {
+    let snapshot = snapshot::read(path).unwrap();
+    let storage = || {
+        snapshot
+            .storage
+            .iter()
+            .map(|entry| (&entry.key, &entry.value))
     };
+    let configs = [
+        ("default", StorageTuning::default()),
+        (
+            "no trie logs",
+            StorageTuning {
+                max_saved_trie_logs: Some(Some(0)),
+                ..Default::default()
+            },
+        ),
+        (
+            "no snapshots",
+            StorageTuning {
+                max_saved_snapshots: Some(Some(0)),
+                ..Default::default()
+            },
+        ),
+        (
+            "snapshot every commit",
+            StorageTuning {
+                snapshot_interval: Some(1),
+                ..Default::default()
+            },
+        ),
+        (
+            "unbounded history",
+            StorageTuning {
+                max_saved_trie_logs: Some(None),
+                max_saved_snapshots: Some(None),
+                snapshot_interval: Some(1),
+            },
+        ),
+    ];
+
+    println!("🔑 {} keys from {}", snapshot.storage.len(), path.display());
+    println!(
+        "{:<22} {:>12} {:>12} {:<66}",
+        "config", "time", "peak rss", "root"
+    );
+    let mut roots = Vec::new();
+    for (name, storage_tuning) in configs {
+        let options = BonsaiOptions {
+            storage: storage_tuning,
+            ..Default::default()
+        };
+        // The peak is reset before each config so none inherits the memory of the previous
+        let before = reset_peak_memory().then(resident_memory).flatten();
+        let start = Instant::now();
+        let root = bonsai_root(storage(), &ProgressBar::hidden(), &options).unwrap();
+        let elapsed = start.elapsed();
+
+        let rss = before
+            .zip(peak_memory())
+            .map_or("-".to_string(), |(before, peak)| {
+                let grown = peak.saturating_sub(before);
+                format!("{:.1} MiB", grown as f64 / (1 << 20) as f64)
+            });
+        println!(
+            "{name:<22} {:>12} {rss:>12} {:<66}",
+            time_unit.format(elapsed, precision),
+            root_format.format(&root)
+        );
+        roots.push(root);
+    }
+
+    if roots.iter().any(|root| *root != roots[0]) {
+        println!("❌ Bonsai configs disagree on the root");
+        process::exit(1);
+    }
+    println!("✅ all configs give the same root");
+}
+
+/// Resident set size of this process in bytes, only known on Linux.
+fn resident_memory() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf only reads a system constant
+    let page_size = u64::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).ok()?;
+    Some(pages * page_size)
+}
+
+/// Peak resident set size of this process in bytes since the last [`reset_peak_memory`],
+/// only known on Linux.
+fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kib: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim_end()
+        .parse()
+        .ok()?;
+    Some(kib << 10)
+}
+
+/// Lowers the peak resident set size to the current one, returns whether the kernel let
+/// us (Linux 4.0 and later).
+fn reset_peak_memory() -> bool {
+    fs::write("/proc/self/clear_refs", "5").is_ok()
 }
 
-async fn storage_root(contract_address: FieldElement, bar: &ProgressBar) -> Felt {
+fn incremental_bench(
+    blocks: usize,
+    keys: usize,
+    seed: u64,
+    root_format: RootFormat,
+    time_unit: TimeUnit,
+    precision: usize,
+) {
+    // Keys are drawn from a pool twice the size of a block so that blocks overwrite each
+    // other, and one value in eight is a zero clearing its key
+    let mut rng = StdRng::seed_from_u64(seed);
+    let pool = (blocks * keys * 2).max(1) as u64;
+    let writes: Vec<Vec<(FieldElement, FieldElement)>> = (0..blocks)
+        .map(|_| {
+            (0..keys)
+                .map(|_| {
+                    let key = FieldElement::from(rng.gen_range(0..pool));
+                    let value = match rng.gen_range(0..8u64) {
+                        0 => FieldElement::ZERO,
+                        _ => FieldElement::from(rng.gen::<u64>()),
+                    };
+                    (key, value)
+                })
+                .collect()
+        })
+        .collect();
+    println!("🧱 {blocks} blocks of {keys} keys");
+
+    let rebuild = |root_of: &dyn Fn(&HashMap<FieldElement, FieldElement>) -> Felt| {
+        let mut storage = HashMap::new();
+        let start = Instant::now();
+        let roots: Vec<Felt> = writes
+            .iter()
+            .map(|block| {
+                storage.extend(block.iter().copied());
+                root_of(&storage)
+            })
+            .collect();
+        (start.elapsed(), roots)
+    };
+    let (bonsai_rebuild, rebuilt) = rebuild(&|storage| {
+        bonsai_root(storage, &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap()
+    });
+    let (reference_rebuild, reference) = rebuild(&|storage| reference::root(storage.iter()));
+
     let tempdir = tempdir().unwrap();
-    let db = create_rocks_db(tempdir.path()).unwrap();
-    let config = BonsaiStorageConfig::default();
-    let mut bonsai_storage: BonsaiStorage<_, _, Pedersen> =
-        BonsaiStorage::new(RocksDB::new(&db, RocksDBConfig::default()), config).unwrap();
+    let db = create_rocks_db(tempdir.path())
+        .map_err(|e| rocks_db_error(tempdir.path(), e))
+        .unwrap();
+    let mut session = BonsaiSession::new(
+        RocksDB::new(&db, RocksDBConfig::default()),
+        &BonsaiOptions::default(),
+    )
+    .unwrap();
+    let start = Instant::now();
+    let live: Vec<Felt> = writes
+        .iter()
+        .map(|block| {
+            for (key, value) in block {
+                session.insert(*key, *value).unwrap();
+            }
+            session.root().unwrap()
+        })
+        .collect();
+    let bonsai_incremental = start.elapsed();
+
+    println!("{:<22} {:>12}", "strategy", "time");
+    for (name, elapsed) in [
+        ("bonsai rebuild", bonsai_rebuild),
+        ("bonsai incremental", bonsai_incremental),
+        ("reference rebuild", reference_rebuild),
+    ] {
+        println!("{name:<22} {:>12}", time_unit.format(elapsed, precision));
+    }
+    println!(
+        "⚡ incremental is {:.1}x faster than rebuilding",
+        bonsai_rebuild.as_secs_f64() / bonsai_incremental.as_secs_f64()
+    );
+
+    if let Some(block) = (0..blocks).find(|&i| rebuilt[i] != live[i] || rebuilt[i] != reference[i])
+    {
+        println!(
+            "❌ roots differ after block {block}: rebuilt {}, live {}, reference {}",
+            root_format.format(&rebuilt[block]),
+            root_format.format(&live[block]),
+            root_format.format(&reference[block])
+        );
+        process::exit(1);
+    }
+    println!("✅ every strategy gives the same roots");
+}
+
+fn order_check(path: &Path, seed: u64, root_format: RootFormat) {
+    let snapshot = snapshot::read(path).unwrap();
+    let mut storage: Vec<_> = snapshot
+        .storage
+        .iter()
+        .map(|entry| (&entry.key, &entry.value))
+        .collect();
+
+    let mut roots = Vec::new();
+    storage.sort_by_key(|(key, _)| **key);
+    roots.push(("ascending", storage.clone()));
+    storage.reverse();
+    roots.push(("descending", storage.clone()));
+    storage.shuffle(&mut StdRng::seed_from_u64(seed));
+    roots.push(("shuffled", storage));
+
+    let roots: Vec<_> = roots
+        .into_iter()
+        .map(|(order, storage)| {
+            let bonsai = bonsai_root(
+                storage.iter().copied(),
+                &ProgressBar::hidden(),
+                &BonsaiOptions::default(),
+            )
+            .unwrap();
+            let reference = reference::root(storage.iter().copied());
+            (order, bonsai, reference)
+        })
+        .collect();
+
+    println!("🔑 {} keys from {}", snapshot.storage.len(), path.display());
+    println!("{:<12} {:<66} {:<66}", "order", "bonsai", "reference");
+    for (order, bonsai, reference) in &roots {
+        println!(
+            "{order:<12} {:<66} {:<66}",
+            root_format.format(bonsai),
+            root_format.format(reference)
+        );
+    }
+
+    let expected = roots[0].1;
+    if roots
+        .iter()
+        .all(|(_, bonsai, reference)| *bonsai == expected && *reference == expected)
+    {
+        println!("✅ all roots match");
+    } else {
+        println!("❌ roots depend on insertion order");
+        process::exit(1);
+    }
+}
+
+fn offset_probe(path: &Path, offset: usize, root_format: RootFormat) {
+    let snapshot = snapshot::read(path).unwrap();
+    let expected = to_felt(&snapshot.root);
 
-    let contract_storage = CONTRACT_STORAGE.read().await;
-    let contract_storage = contract_storage.get(&contract_address).unwrap();
+    println!("🔑 {} keys from {}", snapshot.storage.len(), path.display());
+    println!(
+        "{:<8} {:<10} {:<66} {:<66} agree",
+        "offset", "collisions", "bonsai", "reference"
+    );
+
+    let mut offset_5_matches = false;
+    for offset in [5, offset] {
+        let storage: HashMap<FieldElement, FieldElement> = snapshot
+            .storage
+            .iter()
+            .map(|entry| (offset_key(&entry.key, offset), entry.value))
+            .collect();
+        let collisions = snapshot.storage.len() - storage.len();
 
-    for (key, value) in contract_storage.read().await.iter() {
-        bar.println(format!("🔑 {key:#x} -> {value:#x}"));
+        let bonsai =
+            bonsai_root(&storage, &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap();
+        let reference = reference::root(&storage);
+        let verdict = match (bonsai == reference, bonsai == expected) {
+            (true, true) => "✅ with the snapshot",
+            (true, false) => "🤝 with each other only",
+            (false, _) => "❌",
+        };
+        println!(
+            "{offset:<8} {collisions:<10} {:<66} {:<66} {verdict}",
+            root_format.format(&bonsai),
+            root_format.format(&reference)
+        );
 
-        let key = key.to_bytes_be().view_bits()[5..].to_owned();
-        let value = Felt::from_bytes_be(&value.to_bytes_be());
+        if offset == 5 {
+            offset_5_matches = bonsai == expected && reference == expected;
+        }
+    }
 
-        bonsai_storage
-            .insert(IDENTIFIER, &key, &value)
-            .expect("Failed to insert into Bonsai storage")
+    if !offset_5_matches {
+        println!("❌ slicing at bit 5 no longer yields the snapshot's root");
+        process::exit(1);
     }
+}
+
+fn endianness_probe(path: &Path, root_format: RootFormat) {
+    let snapshot = snapshot::read(path).unwrap();
+    let expected = to_felt(&snapshot.root);
+
+    println!("🔑 {} keys from {}", snapshot.storage.len(), path.display());
+    println!(
+        "{:<14} {:<12} {:<66} {:<66} agree",
+        "values", "unreadable", "bonsai", "reference"
+    );
 
-    let mut id_builder = BasicIdBuilder::new();
-    bonsai_storage
-        .commit(id_builder.new_id())
-        .expect("Failed to commit to Bonsai storage");
-    bonsai_storage
-        .root_hash(IDENTIFIER)
-        .expect("Failed to retrieve root hash")
+    let mut big_endian_matches = false;
+    for little_endian in [false, true] {
+        let storage: HashMap<FieldElement, FieldElement> = snapshot
+            .storage
+            .iter()
+            .filter_map(|entry| {
+                if !little_endian {
+                    return Some((entry.key, entry.value));
+                }
+                let mut bytes = entry.value.to_bytes_be();
+                bytes.reverse();
+                // Reversed bytes may well exceed the modulus, such values are left out
+                FieldElement::from_bytes_be(&bytes)
+                    .ok()
+                    .map(|value| (entry.key, value))
+            })
+            .collect();
+        let unreadable = snapshot.storage.len() - storage.len();
+
+        let bonsai =
+            bonsai_root(&storage, &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap();
+        let reference = reference::root(&storage);
+        let verdict = match (bonsai == reference, bonsai == expected) {
+            (true, true) => "✅ with the snapshot",
+            (true, false) => "🤝 with each other only",
+            (false, _) => "❌",
+        };
+        let order = if little_endian {
+            "little-endian"
+        } else {
+            "big-endian"
+        };
+        println!(
+            "{order:<14} {unreadable:<12} {:<66} {:<66} {verdict}",
+            root_format.format(&bonsai),
+            root_format.format(&reference)
+        );
+
+        if !little_endian {
+            big_endian_matches = bonsai == expected && reference == expected;
+        }
+    }
+
+    if !big_endian_matches {
+        println!("❌ big-endian values no longer yield the snapshot's root");
+        process::exit(1);
+    }
+}
+
+async fn twins(
+    provider: &SequencerGatewayProvider,
+    contracts: [FieldElement; 2],
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    let mut roots = [Felt::ZERO; 2];
+    for (contract, root) in contracts.iter().zip(roots.iter_mut()) {
+        *root = current_root(*contract, config).await.unwrap();
+    }
+
+    let mut coincidences = Vec::new();
+    let mut active = [false; 2];
+    for i in block_range.clone() {
+        bar.inc(1);
+
+        let state_update = get_state_update(provider, BlockId::Number(i), &config.fetch)
+            .await
+            .unwrap();
+        let mut touched = false;
+        for ((contract, root), active) in contracts.iter().zip(roots.iter_mut()).zip(&mut active) {
+            let changed = apply_state_update(&state_update, *contract, i, bar, config, root)
+                .await
+                .unwrap_or_else(|e| exit_with_error(bar, e))
+                .is_some();
+            touched |= changed;
+            *active |= changed;
+        }
+
+        if touched && roots[0] == roots[1] && roots[0] != Felt::ZERO {
+            print_result(
+                bar,
+                format!(
+                    "🪞 roots coincide at block {i}: {}",
+                    config.root_format.format(&roots[0])
+                ),
+            );
+            coincidences.push(i);
+        }
+    }
+    bar.finish();
+
+    println!(
+        "📜 {:#x} and {:#x}: roots coincided at {} blocks {coincidences:?}",
+        contracts[0],
+        contracts[1],
+        coincidences.len()
+    );
+    if roots[0] == roots[1] {
+        println!("🪞 final roots are equal");
+    } else {
+        println!("🌳 final roots differ");
+    }
+
+    for (contract, active) in contracts.iter().zip(active) {
+        if !active {
+            warn_no_activity(*contract, &block_range);
+        }
+    }
+}
+
+/// Exits unless `block`, up to which a subcommand replays the range, is in it.
+fn require_block_in_range(command: &str, block: u64, block_range: &Range<u64>) {
+    if !block_range.contains(&block) {
+        eprintln!(
+            "{command} --block {block} is outside the range {}..{} of --from and --to",
+            block_range.start, block_range.end
+        );
+        process::exit(2);
+    }
+}
+
+/// Exits on a failed run, e.g. one whose roots mismatched without --no-assert.
+fn exit_with_error(bar: &ProgressBar, e: anyhow::Error) -> ! {
+    bar.finish_and_clear();
+    println!("❌ {e:#}");
+    process::exit(1);
+}
+
+/// Makes a run which never touched `contract_address` explicit, rather than silently empty.
+fn warn_no_activity(contract_address: FieldElement, block_range: &Range<u64>) {
+    println!(
+        "⚠️ contract {contract_address:#x} had no storage activity in blocks {}..{}",
+        block_range.start, block_range.end
+    );
+}
+
+async fn class_trie(
+    provider: &SequencerGatewayProvider,
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+    chain_root: Option<FieldElement>,
+) {
+    let (classes, legacy) = declared_classes(provider, block_range, bar, &config.fetch)
+        .await
+        .unwrap();
+    bar.finish_and_clear();
+
+    let leaves: HashMap<FieldElement, FieldElement> = classes
+        .iter()
+        .map(|(class_hash, compiled_class_hash)| {
+            (*class_hash, class_trie_leaf(compiled_class_hash))
+        })
+        .collect();
+    let bonsai = bonsai_class_root(&leaves, &ProgressBar::hidden()).unwrap();
+    let reference = reference::class_root(&leaves);
+
+    println!(
+        "📚 {} Sierra classes declared, and {legacy} Cairo 0 classes left out of the class trie",
+        leaves.len()
+    );
+    println!("🌳 bonsai root:    {}", config.root_format.format(&bonsai));
+    println!(
+        "🌳 reference root: {}",
+        config.root_format.format(&reference)
+    );
+    if bonsai != reference {
+        println!("❌ roots differ");
+        process::exit(1);
+    }
+    println!("✅ roots match");
+
+    if let Some(chain_root) = chain_root {
+        if bonsai != to_felt(&chain_root) {
+            println!(
+                "❌ the chain's class trie root is {}",
+                config.root_format.format(&to_felt(&chain_root))
+            );
+            process::exit(1);
+        }
+        println!("✅ and match the chain's class trie root");
+    }
+}
+
+async fn union(
+    provider: &SequencerGatewayProvider,
+    members: &[FieldElement],
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    // One pass over the range for all members, rather than one per member
+    let mut storages: Vec<HashMap<FieldElement, FieldElement>> =
+        vec![HashMap::new(); members.len()];
+    for i in block_range.clone() {
+        bar.inc(1);
+        let state_update = get_state_update(provider, BlockId::Number(i), &config.fetch)
+            .await
+            .unwrap();
+        for (member, storage) in members.iter().zip(storages.iter_mut()) {
+            if let Some(storage_diffs) = state_update.state_diff.storage_diffs.get(member) {
+                storage.extend(storage_diffs.iter().map(|diff| (diff.key, diff.value)));
+            }
+        }
+    }
+    bar.finish_and_clear();
+
+    let mut union: HashMap<FieldElement, FieldElement> = HashMap::new();
+    let mut owners: HashMap<FieldElement, FieldElement> = HashMap::new();
+    let mut collisions = 0;
+    for (member, storage) in members.iter().zip(&storages) {
+        let selected: Vec<_> = storage
+            .iter()
+            .filter(|(key, value)| config.filter.matches(key) && **value != FieldElement::ZERO)
+            .collect();
+        println!("📜 {member:#x}: {} nonzero keys", selected.len());
+        if storage.is_empty() {
+            warn_no_activity(*member, &block_range);
+        }
+
+        for (key, value) in selected {
+            if let Some(owner) = owners.insert(*key, *member) {
+                println!("💥 key {key:#x} is stored by both {owner:#x} and {member:#x}");
+                collisions += 1;
+            }
+            union.insert(*key, config.leaf_transform.apply(*value));
+        }
+    }
+    if collisions > 0 {
+        println!("❌ {collisions} keys collide, the union is not a trie of its members");
+        process::exit(1);
+    }
+
+    let bonsai = bonsai_root(&union, &ProgressBar::hidden(), &config.bonsai).unwrap();
+    let reference = reference::root(&union);
+    println!(
+        "🔑 {} keys in the union of {} contracts",
+        union.len(),
+        members.len()
+    );
+    println!("🌳 bonsai root:    {}", config.root_format.format(&bonsai));
+    println!(
+        "🌳 reference root: {}",
+        config.root_format.format(&reference)
+    );
+    if bonsai != reference {
+        println!("❌ roots differ");
+        process::exit(1);
+    }
+    println!("✅ roots match");
+}
+
+/// Prints the timeline rows `(block, keys changed, bonsai root, reference root)` and
+/// writes them to the CSV and JSON files given.
+fn write_timeline(
+    rows: &[(u64, usize, Felt, Felt)],
+    csv: Option<&Path>,
+    json: Option<&Path>,
+    root_format: RootFormat,
+) {
+    println!(
+        "{:<12} {:>8} {:<66} {:<66} match",
+        "block", "keys", "bonsai", "reference"
+    );
+    for (block, keys, bonsai, reference) in rows {
+        println!(
+            "{block:<12} {keys:>8} {:<66} {:<66} {}",
+            root_format.format(bonsai),
+            root_format.format(reference),
+            if bonsai == reference { "✅" } else { "❌" }
+        );
+    }
+
+    if let Some(path) = csv {
+        let mut csv = BufWriter::new(File::create(path).expect("Failed to create timeline CSV"));
+        writeln!(csv, "block,keys_changed,bonsai_root,reference_root,match").unwrap();
+        for (block, keys, bonsai, reference) in rows {
+            writeln!(
+                csv,
+                "{block},{keys},{bonsai:#x},{reference:#x},{}",
+                bonsai == reference
+            )
+            .unwrap();
+        }
+        csv.flush().unwrap();
+    }
+
+    if let Some(path) = json {
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|(block, keys, bonsai, reference)| {
+                serde_json::json!({
+                    "block": block,
+                    "keys_changed": keys,
+                    "bonsai_root": format!("{bonsai:#x}"),
+                    "reference_root": format!("{reference:#x}"),
+                    "match": bonsai == reference,
+                })
+            })
+            .collect();
+        let file = File::create(path).expect("Failed to create timeline JSON");
+        serde_json::to_writer_pretty(BufWriter::new(file), &rows).unwrap();
+    }
+}
+
+/// Bonsai and reference roots at each of `checkpoints`, accumulating once from `from` up
+/// to the last one.
+async fn roots_at(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    from: u64,
+    mut checkpoints: Vec<u64>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) -> Vec<(u64, Felt, Felt)> {
+    checkpoints.sort_unstable();
+    checkpoints.dedup();
+    assert!(
+        checkpoints[0] >= from,
+        "Checkpoint {} is before --from {from}",
+        checkpoints[0]
+    );
+    bar.set_length(checkpoints[checkpoints.len() - 1] + 1 - from);
+
+    let mut rows = Vec::new();
+    let mut next = from;
+    for checkpoint in checkpoints {
+        process_range(
+            provider,
+            contract_address,
+            next..checkpoint + 1,
+            bar,
+            config,
+            None,
+        )
+        .await
+        .unwrap_or_else(|e| exit_with_error(bar, e));
+        next = checkpoint + 1;
+
+        let bonsai = current_root(contract_address, config).await.unwrap();
+        let reference = reference_root(contract_address, config).await;
+        print_result(
+            bar,
+            format!(
+                "🎯 checkpoint {checkpoint}: {}",
+                config.root_format.format(&bonsai)
+            ),
+        );
+        rows.push((checkpoint, bonsai, reference));
+    }
+    bar.finish();
+
+    rows
+}
+
+async fn root_delta(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    from: u64,
+    blocks: [u64; 2],
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    assert!(
+        blocks[0] < blocks[1],
+        "--from-block must be before --to-block"
+    );
+    let rows = roots_at(
+        provider,
+        contract_address,
+        from,
+        blocks.to_vec(),
+        bar,
+        config,
+    )
+    .await;
+    let [(a, root_a, reference_a), (b, root_b, reference_b)] = rows[..] else {
+        unreachable!("two distinct checkpoints yield two rows");
+    };
+
+    println!("📜 contract {contract_address:#x}");
+    println!(
+        "🌳 root at block {a}: {}",
+        config.root_format.format(&root_a)
+    );
+    println!(
+        "🌳 root at block {b}: {}",
+        config.root_format.format(&root_b)
+    );
+    println!("🔁 changed: {}", root_a != root_b);
+
+    if root_a != reference_a || root_b != reference_b {
+        println!("❌ the reference trie disagrees with Bonsai");
+        process::exit(1);
+    }
+}
+
+async fn checkpoints(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    from: u64,
+    checkpoints: Vec<u64>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    let rows = roots_at(provider, contract_address, from, checkpoints, bar, config).await;
+
+    println!("{:<12} {:<66} {:<66} match", "block", "bonsai", "reference");
+    for (block, bonsai, reference) in &rows {
+        println!(
+            "{block:<12} {:<66} {:<66} {}",
+            config.root_format.format(bonsai),
+            config.root_format.format(reference),
+            if bonsai == reference { "✅" } else { "❌" }
+        );
+    }
+
+    if rows
+        .iter()
+        .any(|(_, bonsai, reference)| bonsai != reference)
+    {
+        process::exit(1);
+    }
+}
+
+async fn storage_at(
+    contract_address: FieldElement,
+    block: u64,
+    rpc_url: &Url,
+    keys_file: Option<&Path>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    let accumulated = selected_storage(contract_address, config).await;
+    let keys: Vec<FieldElement> = match keys_file {
+        Some(path) => fs::read_to_string(path)
+            .expect("Failed to read keys file")
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                FieldElement::from_hex_be(line).unwrap_or_else(|_| {
+                    eprintln!("invalid key in {}: {line}", path.display());
+                    process::exit(2);
+                })
+            })
+            .filter(|key| config.filter.matches(key))
+            .collect(),
+        None => accumulated.iter().map(|(key, _)| *key).collect(),
+    };
+
+    bar.set_position(0);
+    bar.set_length(keys.len() as u64);
+    let absolute: Vec<_> = rpc::storage_at(rpc_url, contract_address, &keys, block, bar)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(key, value)| (key, config.leaf_transform.apply(value)))
+        .collect();
+    bar.finish_and_clear();
+
+    let accumulated_root = current_root(contract_address, config).await.unwrap();
+    let absolute_root = bonsai_root(
+        absolute.iter().map(|(key, value)| (key, value)),
+        &ProgressBar::hidden(),
+        &config.bonsai,
+    )
+    .unwrap();
+
+    println!(
+        "📜 contract {contract_address:#x} at block {block}, {} keys",
+        keys.len()
+    );
+    println!(
+        "🌳 accumulated root: {}",
+        config.root_format.format(&accumulated_root)
+    );
+    println!(
+        "🌳 getStorageAt root: {}",
+        config.root_format.format(&absolute_root)
+    );
+    if accumulated_root == absolute_root {
+        println!("✅ roots match");
+    } else {
+        println!("❌ roots differ");
+        process::exit(1);
+    }
+}
+
+async fn audit(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    markdown: Option<&Path>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    let mut report = Report::new(contract_address, block_range.clone());
+    let mut on_block = |result: &BlockResult| {
+        report.keys_written += result.written as u64;
+        if result.mismatch {
+            report.mismatches.push(result.block);
+        }
+    };
+    let stats = process_range(
+        provider,
+        contract_address,
+        block_range,
+        bar,
+        config,
+        Some(&mut on_block as BlockHook),
+    )
+    .await
+    .unwrap_or_else(|e| exit_with_error(bar, e));
+    bar.finish_and_clear();
+
+    // A run cut short by --deadline only vouches for the blocks it got through
+    if let Some(stopped_at) = stats.stopped_at {
+        report.range.end = stopped_at;
+    }
+    report.blocks = stats.blocks;
+    report.blocks_touched = stats.blocks_with_diffs;
+    report.unique_keys = selected_storage(contract_address, config).await.len();
+    report.bonsai_root = current_root(contract_address, config).await.unwrap();
+    report.reference_root = reference_root(contract_address, config).await;
+
+    print!("{}", report.terminal(config.root_format));
+    if let Some(path) = markdown {
+        fs::write(path, report.markdown(config.root_format)).expect("Failed to write report");
+        println!("📝 report written to {}", path.display());
+    }
+    if !report.passed() {
+        process::exit(1);
+    }
+}
+
+async fn cross_provider(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    rpc_url: &Url,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    let mut from_gateway = HashMap::new();
+    let mut from_node = HashMap::new();
+    let mut disagreeing = Vec::new();
+
+    for block in block_range {
+        let state_update = get_state_update(provider, BlockId::Number(block), &config.fetch)
+            .await
+            .unwrap();
+        let gateway_diff: BTreeMap<_, _> = state_update
+            .state_diff
+            .storage_diffs
+            .get(&contract_address)
+            .into_iter()
+            .flatten()
+            .map(|diff| (diff.key, diff.value))
+            .collect();
+        let node_diff: BTreeMap<_, _> = rpc::storage_diff(rpc_url, contract_address, block)
+            .await
+            .unwrap()
+            .into_iter()
+            .collect();
+        bar.inc(1);
+
+        if gateway_diff != node_diff {
+            let differing = gateway_diff
+                .keys()
+                .chain(node_diff.keys())
+                .filter(|key| gateway_diff.get(key) != node_diff.get(key))
+                .collect::<BTreeSet<_>>()
+                .len();
+            print_result(
+                bar,
+                format!(
+                    "❌ block {block}: the gateway and the node disagree on {differing} keys, \
+                {} written per the gateway, {} per the node",
+                    gateway_diff.len(),
+                    node_diff.len()
+                ),
+            );
+            disagreeing.push(block);
+        } else if !gateway_diff.is_empty() {
+            print_result(
+                bar,
+                format!(
+                    "✅ block {block}: both sources write the same {} keys",
+                    gateway_diff.len()
+                ),
+            );
+        }
+
+        let transform = |(key, value)| (key, config.leaf_transform.apply(value));
+        from_gateway.extend(gateway_diff.into_iter().map(transform));
+        from_node.extend(node_diff.into_iter().map(transform));
+    }
+    bar.finish_and_clear();
+
+    let gateway_root = bonsai_root(&from_gateway, &ProgressBar::hidden(), &config.bonsai).unwrap();
+    let node_root = bonsai_root(&from_node, &ProgressBar::hidden(), &config.bonsai).unwrap();
+    println!(
+        "🌳 gateway root: {}",
+        config.root_format.format(&gateway_root)
+    );
+    println!("🌳 node root:    {}", config.root_format.format(&node_root));
+
+    if !disagreeing.is_empty() {
+        println!(
+            "❌ the sources disagree on {} blocks: {disagreeing:?}",
+            disagreeing.len()
+        );
+        process::exit(1);
+    }
+    if gateway_root != node_root {
+        println!("❌ roots differ although every diff matched");
+        process::exit(1);
+    }
+    println!("✅ both sources agree on every block");
+}
+
+async fn zero_semantics(
+    contract_address: FieldElement,
+    block: u64,
+    chain_root: Option<FieldElement>,
+    config: &RunConfig,
+) {
+    let storage = selected_storage(contract_address, config).await;
+    let zeros = storage
+        .iter()
+        .filter(|(_, value)| *value == FieldElement::ZERO)
+        .count();
+    let storage = || storage.iter().map(|(key, value)| (key, value));
+
+    let bonsai = bonsai_root(storage(), &ProgressBar::hidden(), &config.bonsai).unwrap();
+    let roots = [
+        ("bonsai", "delete", Some(bonsai)),
+        // Bonsai removes the leaf of a zero value on insertion, it has no store-zero mode
+        ("bonsai", "store-zero", None),
+        ("reference", "delete", Some(reference::root(storage()))),
+        (
+            "reference",
+            "store-zero",
+            Some(reference::root_storing_zeros(storage())),
+        ),
+    ];
+    let chain_root = chain_root.map(|root| to_felt(&root));
+
+    println!(
+        "📜 contract {contract_address:#x} at block {block}, {} keys of which {zeros} are zero",
+        storage().count()
+    );
+    if let Some(chain_root) = &chain_root {
+        println!("🎯 chain root: {}", config.root_format.format(chain_root));
+    }
+    println!("{:<12} {:<12} {:<66}", "engine", "zero writes", "root");
+    for (engine, semantics, root) in roots {
+        let Some(root) = root else {
+            println!("{engine:<12} {semantics:<12} unsupported");
+            continue;
+        };
+        let matches = if chain_root == Some(root) {
+            " 🎯"
+        } else {
+            ""
+        };
+        println!(
+            "{engine:<12} {semantics:<12} {:<66}{matches}",
+            config.root_format.format(&root)
+        );
+    }
+
+    // With no zero value there is nothing to delete, every reading must give the same root
+    if zeros == 0 {
+        let mut computed = roots.iter().filter_map(|(_, _, root)| *root);
+        let first = computed.next().unwrap();
+        if computed.all(|root| root == first) {
+            println!("✅ no zero values, and both readings agree as they must");
+        } else {
+            println!(
+                "❌ roots differ without any zero value, the deletion path alters nonzero data"
+            );
+            process::exit(1);
+        }
+    }
+}
+
+async fn latest_delta(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    // `run` rejects empty ranges, so there is a last block
+    let block = block_range.end - 1;
+    process_range(
+        provider,
+        contract_address,
+        block_range.start..block,
+        bar,
+        config,
+        None,
+    )
+    .await
+    .unwrap_or_else(|e| exit_with_error(bar, e));
+    let before = current_root(contract_address, config).await.unwrap();
+
+    let state_update = get_state_update(provider, BlockId::Number(block), &config.fetch)
+        .await
+        .unwrap();
+    bar.inc(1);
+    bar.finish_and_clear();
+    let Some(storage_diffs) = state_update.state_diff.storage_diffs.get(&contract_address) else {
+        println!(
+            "🧱 block {block} does not touch the contract, its root stays {}",
+            config.root_format.format(&before)
+        );
+        return;
+    };
+
+    let previous: Vec<Option<FieldElement>> =
+        match CONTRACT_STORAGE.read().await.get(&contract_address) {
+            Some(storage) => {
+                let storage = storage.read().await;
+                storage_diffs
+                    .iter()
+                    .map(|diff| storage.get(&diff.key).copied())
+                    .collect()
+            }
+            None => vec![None; storage_diffs.len()],
+        };
+
+    let mut after = before;
+    apply_state_update(
+        &state_update,
+        contract_address,
+        block,
+        &ProgressBar::hidden(),
+        config,
+        &mut after,
+    )
+    .await
+    .unwrap_or_else(|e| exit_with_error(bar, e));
+
+    println!("📜 contract {contract_address:#x}, block {block}");
+    println!("🌳 root before: {}", config.root_format.format(&before));
+    println!("🌳 root after:  {}", config.root_format.format(&after));
+    println!("🔑 {} keys written", storage_diffs.len());
+    for (diff, previous) in storage_diffs.iter().zip(previous) {
+        let previous = previous.map_or("unset".to_string(), |value| format!("{value:#x}"));
+        let unchanged = if previous == format!("{:#x}", diff.value) {
+            " (unchanged)"
+        } else {
+            ""
+        };
+        println!(
+            "   {:#x}: {previous} -> {:#x}{unchanged}",
+            diff.key, diff.value
+        );
+    }
+}
+
+/// Inserts the contract's diffs of the range block by block into one live Bonsai trie.
+/// With `commit_every`, commits every that many blocks and compares the live root with
+/// the reference root at each commit, then commits once more when the range ends or on
+/// Ctrl-C. With `verify_rebuild_final`, finally checks the live root against a Bonsai trie
+/// rebuilt from the accumulated storage and against the reference trie.
+async fn live_trie(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    commit_every: Option<u64>,
+    verify_rebuild_final: bool,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    let tempdir = tempdir().unwrap();
+    let db = create_rocks_db(tempdir.path())
+        .map_err(|e| rocks_db_error(tempdir.path(), e))
+        .unwrap();
+    let mut session =
+        BonsaiSession::new(RocksDB::new(&db, RocksDBConfig::default()), &config.bonsai).unwrap();
+
+    // Storage from a snapshot goes in first
+    let loaded = CONTRACT_STORAGE
+        .read()
+        .await
+        .contains_key(&contract_address);
+    if loaded {
+        for (key, value) in selected_storage(contract_address, config).await {
+            session.insert(key, value).unwrap();
+        }
+    }
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut touched = loaded;
+    let mut last_applied = None;
+    let mut uncommitted_blocks = 0;
+    let mut failed = false;
+    for i in block_range.clone() {
+        let state_update = tokio::select! {
+            biased;
+            _ = &mut ctrl_c => {
+                print_result(bar, format!("🛑 interrupted before block {i}"));
+                break;
+            }
+            state_update = get_state_update(provider, BlockId::Number(i), &config.fetch) => {
+                state_update.unwrap()
+            }
+        };
+        bar.inc(1);
+        last_applied = Some(i);
+
+        if let Some(storage_diffs) = state_update.state_diff.storage_diffs.get(&contract_address) {
+            touched = true;
+            save_storage_update(contract_address, storage_diffs).await;
+            for StorageDiff { key, value } in storage_diffs {
+                if config.filter.matches(key) {
+                    session
+                        .insert(*key, config.leaf_transform.apply(*value))
+                        .unwrap();
+                }
+            }
+        }
+
+        uncommitted_blocks += 1;
+        if commit_every == Some(uncommitted_blocks) {
+            failed |= !commit_and_compare(&mut session, contract_address, i, bar, config).await;
+            uncommitted_blocks = 0;
+        }
+    }
+
+    // Whatever was applied since the last commit must not be lost
+    if let (Some(_), Some(block)) = (commit_every, last_applied) {
+        failed |= !commit_and_compare(&mut session, contract_address, block, bar, config).await;
+    }
+    bar.finish_and_clear();
+    if !touched {
+        warn_no_activity(contract_address, &block_range);
+        return;
+    }
+
+    if verify_rebuild_final {
+        let format = |root: &Felt| config.root_format.format(root);
+        let live = session.root().unwrap();
+        let rebuilt = current_root(contract_address, config).await.unwrap();
+        let reference = reference_root(contract_address, config).await;
+        println!(
+            "🌳 live root at block {}: {}",
+            last_applied.unwrap_or(block_range.start),
+            format(&live)
+        );
+        println!("🏗️ rebuilt root: {}", format(&rebuilt));
+        println!("📐 reference root: {}", format(&reference));
+
+        if live != rebuilt || live != reference {
+            println!("❌ the live trie diverged from the rebuilt tries");
+            failed = true;
+        } else {
+            println!("✅ the live trie matches the rebuilt tries");
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+/// Commits `session` under a fresh id after `block`, and returns whether its root equals
+/// the reference root of the accumulated storage.
+async fn commit_and_compare(
+    session: &mut BonsaiSession<'_>,
+    contract_address: FieldElement,
+    block: u64,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) -> bool {
+    let id = session.commit().unwrap();
+    let live = session.root().unwrap();
+    let reference = if CONTRACT_STORAGE
+        .read()
+        .await
+        .contains_key(&contract_address)
+    {
+        reference_root(contract_address, config).await
+    } else {
+        Felt::ZERO
+    };
+
+    let format = |root: &Felt| config.root_format.format(root);
+    if live == reference {
+        print_result(
+            bar,
+            format!("💾 commit {id:?} at block {block}: {} ✅", format(&live)),
+        );
+        true
+    } else {
+        print_result(
+            bar,
+            format!(
+                "❌ commit {id:?} at block {block}: live root {}, reference {}",
+                format(&live),
+                format(&reference)
+            ),
+        );
+        false
+    }
+}
+
+async fn deploy_check(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    let mut root = current_root(contract_address, config).await.unwrap();
+
+    for i in block_range.clone() {
+        bar.inc(1);
+
+        let state_update = get_state_update(provider, BlockId::Number(i), &config.fetch)
+            .await
+            .unwrap();
+        let deployed = state_update
+            .state_diff
+            .deployed_contracts
+            .iter()
+            .any(|contract| contract.address == contract_address);
+        apply_state_update(&state_update, contract_address, i, bar, config, &mut root)
+            .await
+            .unwrap_or_else(|e| exit_with_error(bar, e));
+        if !deployed {
+            continue;
+        }
+        bar.finish_and_clear();
+
+        let keys = selected_storage(contract_address, config).await.len();
+        let reference = reference_root(contract_address, config).await;
+        println!("📜 contract {contract_address:#x} deployed at block {i} with {keys} keys");
+        println!("🌳 bonsai root:    {}", config.root_format.format(&root));
+        println!(
+            "🌳 reference root: {}",
+            config.root_format.format(&reference)
+        );
+        if root != reference {
+            println!("❌ roots differ at the deployment block");
+            process::exit(1);
+        }
+        println!("✅ roots match at the deployment block");
+        return;
+    }
+
+    bar.finish_and_clear();
+    println!(
+        "❌ contract {contract_address:#x} was not deployed in blocks {}..{}",
+        block_range.start, block_range.end
+    );
+    process::exit(1);
+}
+
+async fn reverse_check(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    let mut diffs: Vec<Vec<(FieldElement, FieldElement)>> = Vec::new();
+    for i in block_range {
+        bar.inc(1);
+
+        let state_update = get_state_update(provider, BlockId::Number(i), &config.fetch)
+            .await
+            .unwrap();
+        if let Some(storage_diffs) = state_update.state_diff.storage_diffs.get(&contract_address) {
+            diffs.push(
+                storage_diffs
+                    .iter()
+                    .map(|diff| (diff.key, diff.value))
+                    .collect(),
+            );
+        }
+    }
+    bar.finish_and_clear();
+
+    let mut writes: HashMap<FieldElement, usize> = HashMap::new();
+    for (key, _) in diffs.iter().flatten() {
+        *writes.entry(*key).or_default() += 1;
+    }
+    let overlapping = writes.values().filter(|count| **count > 1).count();
+
+    let forward = accumulated_root(diffs.iter(), config);
+    let reverse = accumulated_root(diffs.iter().rev(), config);
+
+    println!(
+        "📜 contract {contract_address:#x}: {} blocks with diffs, {} keys written",
+        diffs.len(),
+        writes.len()
+    );
+    println!("🌳 forward root: {}", config.root_format.format(&forward));
+    println!("🌳 reverse root: {}", config.root_format.format(&reverse));
+
+    if overlapping > 0 {
+        println!(
+            "⚠️ {overlapping} keys are written in more than one block, the roots are expected to differ"
+        );
+        if forward == reverse {
+            println!("✅ roots match anyway");
+        } else {
+            println!("🔁 roots differ, as expected");
+        }
+    } else if forward == reverse {
+        println!("✅ roots match");
+    } else {
+        println!("❌ roots depend on block order although no key is written twice");
+        process::exit(1);
+    }
+}
+
+async fn bisect(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    let mut blocks = Vec::new();
+    let mut diffs: Vec<Vec<(FieldElement, FieldElement)>> = Vec::new();
+    for i in block_range.clone() {
+        bar.inc(1);
+
+        let state_update = get_state_update(provider, BlockId::Number(i), &config.fetch)
+            .await
+            .unwrap();
+        if let Some(storage_diffs) = state_update.state_diff.storage_diffs.get(&contract_address) {
+            blocks.push(i);
+            diffs.push(
+                storage_diffs
+                    .iter()
+                    .map(|diff| (diff.key, diff.value))
+                    .collect(),
+            );
+        }
+    }
+    bar.finish_and_clear();
+
+    if blocks.is_empty() {
+        warn_no_activity(contract_address, &block_range);
+        return;
+    }
+
+    let mut probes = 0;
+    // Roots after the first `n` blocks with diffs
+    let mut roots_after = |n: usize| {
+        probes += 1;
+        let storage: HashMap<FieldElement, FieldElement> = diffs[..n]
+            .iter()
+            .flatten()
+            .filter(|(key, _)| config.filter.matches(key))
+            .map(|(key, value)| (*key, config.leaf_transform.apply(*value)))
+            .collect();
+        let bonsai = bonsai_root(&storage, &ProgressBar::hidden(), &config.bonsai).unwrap();
+        (bonsai, reference::root(&storage))
+    };
+
+    let (bonsai, reference) = roots_after(blocks.len());
+    if bonsai == reference {
+        println!(
+            "✅ roots agree after block {}, nothing to bisect",
+            blocks[blocks.len() - 1]
+        );
+        return;
+    }
+
+    // Invariant: roots agree after `good` blocks (trivially so for none) and differ after `bad`
+    let (mut good, mut bad) = (0, blocks.len());
+    while bad - good > 1 {
+        let mid = (good + bad) / 2;
+        let (bonsai, reference) = roots_after(mid);
+        if bonsai == reference {
+            good = mid;
+        } else {
+            bad = mid;
+        }
+    }
+
+    let (bonsai, reference) = roots_after(bad);
+    println!(
+        "📜 contract {contract_address:#x}: {} blocks with diffs, {probes} rebuilds",
+        blocks.len()
+    );
+    println!("💥 roots first diverge at block {}", blocks[bad - 1]);
+    println!("🌳 bonsai root:    {}", config.root_format.format(&bonsai));
+    println!(
+        "🌳 reference root: {}",
+        config.root_format.format(&reference)
+    );
+    process::exit(1);
+}
+
+async fn reorg(
+    provider: &SequencerGatewayProvider,
+    contract_address: FieldElement,
+    block_range: Range<u64>,
+    revert_to: u64,
+    alternate: &Path,
+    bar: &ProgressBar,
+    config: &RunConfig,
+) {
+    assert!(
+        block_range.contains(&revert_to),
+        "--revert-to must be within the range"
+    );
+    let file = File::open(alternate).expect("Failed to open alternate blocks");
+    let alternate: BTreeMap<u64, Vec<StorageDiff>> =
+        serde_json::from_reader(io::BufReader::new(file))
+            .expect("Failed to parse alternate blocks");
+    assert!(
+        alternate.keys().all(|block| *block > revert_to),
+        "Alternate blocks must come after --revert-to"
+    );
+
+    // A live Bonsai trie committing once per block, reverted through Bonsai's own history
+    let tempdir = tempdir().unwrap();
+    let db = create_rocks_db(tempdir.path())
+        .map_err(|e| rocks_db_error(tempdir.path(), e))
+        .unwrap();
+    let mut session =
+        BonsaiSession::new(RocksDB::new(&db, RocksDBConfig::default()), &config.bonsai).unwrap();
+    let apply = |session: &mut BonsaiSession, diffs: &[StorageDiff]| {
+        for StorageDiff { key, value } in diffs {
+            if config.filter.matches(key) {
+                session
+                    .insert(*key, config.leaf_transform.apply(*value))
+                    .unwrap();
+            }
+        }
+        session.commit().unwrap()
+    };
+
+    // Every block's writes, each along with the value it overwrote to undo it
+    let mut storage: HashMap<FieldElement, FieldElement> = HashMap::new();
+    let mut journal: Vec<(u64, Vec<(FieldElement, FieldElement, Option<FieldElement>)>)> =
+        Vec::new();
+    let mut commits = HashMap::new();
+    for i in block_range.clone() {
+        bar.inc(1);
+
+        let state_update = get_state_update(provider, BlockId::Number(i), &config.fetch)
+            .await
+            .unwrap();
+        let storage_diffs = state_update
+            .state_diff
+            .storage_diffs
+            .get(&contract_address)
+            .map_or(&[][..], Vec::as_slice);
+        if !storage_diffs.is_empty() {
+            let writes = storage_diffs
+                .iter()
+                .map(|diff| (diff.key, diff.value, storage.insert(diff.key, diff.value)))
+                .collect();
+            journal.push((i, writes));
+        }
+        commits.insert(i, apply(&mut session, storage_diffs));
+    }
+    bar.finish_and_clear();
+
+    let format = |root: &Felt| config.root_format.format(root);
+    let roots = |storage: &HashMap<FieldElement, FieldElement>| {
+        let selected: HashMap<FieldElement, FieldElement> = storage
+            .iter()
+            .filter(|(key, _)| config.filter.matches(key))
+            .map(|(key, value)| (*key, config.leaf_transform.apply(*value)))
+            .collect();
+        let bonsai = bonsai_root(&selected, &ProgressBar::hidden(), &config.bonsai).unwrap();
+        (bonsai, reference::root(&selected))
+    };
+    let mut failed = false;
+
+    let (canonical, _) = roots(&storage);
+    let live = session.root().unwrap();
+    println!(
+        "⛓️ canonical root at block {}: {}",
+        block_range.end - 1,
+        format(&canonical)
+    );
+    if live != canonical {
+        println!("❌ live Bonsai root: {}", format(&live));
+        failed = true;
+    }
+
+    // Undo blocks newest first, and writes within a block last first, so that a key
+    // written several times gets its value from before the first of them back
+    while let Some((block, writes)) = journal.last() {
+        if *block <= revert_to {
+            break;
+        }
+        for (key, _, previous) in writes.iter().rev() {
+            match previous {
+                Some(previous) => storage.insert(*key, *previous),
+                None => storage.remove(key),
+            };
+        }
+        journal.pop();
+    }
+    session.revert_to(commits[&revert_to]).unwrap();
+
+    let rebuilt: HashMap<FieldElement, FieldElement> = journal
+        .iter()
+        .flat_map(|(_, writes)| writes.iter().map(|(key, value, _)| (*key, *value)))
+        .collect();
+    let (reverted, reference) = roots(&storage);
+    let live = session.root().unwrap();
+    println!("⏪ reverted to block {revert_to}: {}", format(&reverted));
+    if storage != rebuilt {
+        println!("❌ the reverted storage differs from the one rebuilt up to block {revert_to}");
+        failed = true;
+    }
+    if reverted != reference {
+        println!("❌ reference root: {}", format(&reference));
+        failed = true;
+    }
+    if live != reverted {
+        println!("❌ Bonsai's own revert gives {}", format(&live));
+        failed = true;
+    }
+
+    for (block, diffs) in &alternate {
+        storage.extend(diffs.iter().map(|diff| (diff.key, diff.value)));
+        apply(&mut session, diffs);
+        let (bonsai, reference) = roots(&storage);
+        let live = session.root().unwrap();
+        if bonsai == reference && bonsai == live {
+            println!("🔀 alternate block {block}: {} ✅", format(&bonsai));
+        } else {
+            println!(
+                "🔀 alternate block {block}: bonsai {}, live bonsai {}, reference {} ❌",
+                format(&bonsai),
+                format(&live),
+                format(&reference)
+            );
+            failed = true;
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+    println!(
+        "✅ reverted storage matches the rebuild, Bonsai's revert matches both, and the \
+        engines agree along the {} alternate blocks",
+        alternate.len()
+    );
+}
+
+/// Bonsai root of the storage left by applying `diffs` in iteration order.
+fn accumulated_root<'a>(
+    diffs: impl Iterator<Item = &'a Vec<(FieldElement, FieldElement)>>,
+    config: &RunConfig,
+) -> Felt {
+    let storage: HashMap<FieldElement, FieldElement> = diffs
+        .flatten()
+        .filter(|(key, _)| config.filter.matches(key))
+        .map(|(key, value)| (*key, config.leaf_transform.apply(*value)))
+        .collect();
+    bonsai_root(&storage, &ProgressBar::hidden(), &config.bonsai).unwrap()
 }
@@ -0,0 +1,101 @@
+//! Run counters exposed in the Prometheus text format, for scraping long runs.
+//!
+//! The endpoint is a bare HTTP responder on a tokio listener: whatever the request, it
+//! answers with the current counters.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use crate::BlockResult;
+
+#[derive(Default)]
+pub struct Metrics {
+    blocks: AtomicU64,
+    blocks_with_diffs: AtomicU64,
+    keys_inserted: AtomicU64,
+    mismatches: AtomicU64,
+    last_block: AtomicU64,
+    /// Processing time of the last block, in microseconds
+    last_block_latency: AtomicU64,
+}
+
+impl Metrics {
+    /// Records that block `block` was processed in `latency`, with `result` if it touched
+    /// the contract.
+    pub fn record(&self, block: u64, latency: Duration, result: Option<&BlockResult>) {
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+        if let Some(result) = result {
+            self.blocks_with_diffs.fetch_add(1, Ordering::Relaxed);
+            self.keys_inserted
+                .fetch_add(result.keys as u64, Ordering::Relaxed);
+            self.mismatches
+                .fetch_add(result.mismatch as u64, Ordering::Relaxed);
+        }
+        self.last_block.store(block, Ordering::Relaxed);
+        self.last_block_latency
+            .store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let latency = self.last_block_latency.load(Ordering::Relaxed) as f64 / 1e6;
+
+        format!(
+            "# TYPE deoxys_test_blocks_total counter\n\
+            deoxys_test_blocks_total {}\n\
+            # TYPE deoxys_test_blocks_with_diffs_total counter\n\
+            deoxys_test_blocks_with_diffs_total {}\n\
+            # TYPE deoxys_test_keys_inserted_total counter\n\
+            deoxys_test_keys_inserted_total {}\n\
+            # TYPE deoxys_test_mismatches_total counter\n\
+            deoxys_test_mismatches_total {}\n\
+            # TYPE deoxys_test_last_block gauge\n\
+            deoxys_test_last_block {}\n\
+            # TYPE deoxys_test_last_block_seconds gauge\n\
+            deoxys_test_last_block_seconds {latency}\n",
+            self.blocks.load(Ordering::Relaxed),
+            self.blocks_with_diffs.load(Ordering::Relaxed),
+            self.keys_inserted.load(Ordering::Relaxed),
+            self.mismatches.load(Ordering::Relaxed),
+            self.last_block.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Binds `addr` and serves `metrics` from a spawned task until the process exits.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind the metrics endpoint on {addr}"))?;
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let body = metrics.render();
+
+            tokio::spawn(async move {
+                // The request itself doesn't matter, but reading it keeps clients happy
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                    Content-Type: text/plain; version=0.0.4\r\n\
+                    Content-Length: {}\r\n\
+                    Connection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+
+    Ok(())
+}
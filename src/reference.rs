@@ -0,0 +1,266 @@
+//! A deliberately naive Starknet storage trie, independent from Bonsai.
+//!
+//! The root is recomputed from scratch over the fixed 251-bit height, following the
+//! Merkle-Patricia rules of the Starknet spec:
+//!
+//! - a binary node hashes as `H(left, right)`
+//! - an edge node of `length` bits hashes as `H(child, path) + length`
+//! - the empty trie has a root of zero, and zero values are not leaves
+//!
+//! `H` is Pedersen for storage tries, and Poseidon for the class trie.
+//!
+//! It is slow and only meant as a third opinion: if it disagrees with Bonsai, either
+//! Bonsai or our reading of the spec is wrong.
+
+use bitvec::prelude::{BitSlice, BitVec, Msb0};
+use bitvec::view::BitView;
+use starknet::core::types::FieldElement;
+use starknet_types_core::felt::Felt;
+use starknet_types_core::hash::{Pedersen, Poseidon, StarkHash};
+
+const HEIGHT: usize = 251;
+
+type Leaf = (BitVec<u8, Msb0>, Felt);
+
+/// Computes the storage root of `storage` without building any intermediate state.
+pub fn root<'a>(storage: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>) -> Felt {
+    root_of::<Pedersen>(storage, false)
+}
+
+/// Same as [`root`], hashing nodes with Poseidon as the class trie does. Values are the
+/// class trie leaves, see [`crate::class_trie_leaf`].
+pub fn class_root<'a>(
+    leaves: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>,
+) -> Felt {
+    root_of::<Poseidon>(leaves, false)
+}
+
+/// Same as [`root`], but zero values are kept as leaves instead of deleting their key.
+/// This is not what the spec says, and only exists to tell the two readings apart.
+pub fn root_storing_zeros<'a>(
+    storage: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>,
+) -> Felt {
+    root_of::<Pedersen>(storage, true)
+}
+
+/// Node counts of a trie.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Shape {
+    pub leaves: usize,
+    pub binary_nodes: usize,
+    pub edge_nodes: usize,
+}
+
+/// Shape of the trie [`root`] hashes for `storage`.
+pub fn shape<'a>(storage: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>) -> Shape {
+    let leaves = leaves(storage, false);
+    let mut shape = Shape {
+        leaves: leaves.len(),
+        ..Default::default()
+    };
+    if !leaves.is_empty() {
+        count_nodes(&leaves, 0, &mut shape);
+    }
+    shape
+}
+
+fn count_nodes(leaves: &[Leaf], depth: usize, shape: &mut Shape) {
+    if depth == HEIGHT {
+        return;
+    }
+
+    let common = common_prefix(leaves, depth);
+    if common > 0 {
+        shape.edge_nodes += 1;
+        return count_nodes(leaves, depth + common, shape);
+    }
+
+    shape.binary_nodes += 1;
+    let split = leaves.partition_point(|(key, _)| !key[depth]);
+    count_nodes(&leaves[..split], depth + 1, shape);
+    count_nodes(&leaves[split..], depth + 1, shape);
+}
+
+fn root_of<'a, H: StarkHash>(
+    storage: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>,
+    keep_zeros: bool,
+) -> Felt {
+    let leaves = leaves(storage, keep_zeros);
+    if leaves.is_empty() {
+        return Felt::ZERO;
+    }
+
+    subtree::<H>(&leaves, 0)
+}
+
+/// Root of leaves given by their trie path rather than their key, so that tests can
+/// build paths of their own.
+#[cfg(test)]
+pub(crate) fn root_of_paths(leaves: impl IntoIterator<Item = (BitVec<u8, Msb0>, Felt)>) -> Felt {
+    let mut leaves: Vec<Leaf> = leaves
+        .into_iter()
+        .filter(|(_, value)| *value != Felt::ZERO)
+        .collect();
+    if leaves.is_empty() {
+        return Felt::ZERO;
+    }
+
+    leaves.sort_by(|(a, _), (b, _)| a.cmp(b));
+    subtree::<Pedersen>(&leaves, 0)
+}
+
+/// Sorted leaves of `storage`.
+fn leaves<'a>(
+    storage: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>,
+    keep_zeros: bool,
+) -> Vec<Leaf> {
+    let mut leaves: Vec<Leaf> = storage
+        .into_iter()
+        .filter(|(_, value)| keep_zeros || **value != FieldElement::ZERO)
+        .map(|(key, value)| {
+            (
+                key.to_bytes_be().view_bits::<Msb0>()[5..].to_owned(),
+                Felt::from_bytes_be(&value.to_bytes_be()),
+            )
+        })
+        .collect();
+    leaves.sort_by(|(a, _), (b, _)| a.cmp(b));
+    leaves
+}
+
+/// Hash of the node at `depth` spanning `leaves`, which must be sorted and non-empty.
+fn subtree<H: StarkHash>(leaves: &[Leaf], depth: usize) -> Felt {
+    if depth == HEIGHT {
+        return leaves[0].1;
+    }
+
+    let common = common_prefix(leaves, depth);
+    if common > 0 {
+        let child = subtree::<H>(leaves, depth + common);
+        let path = path_felt(&leaves[0].0[depth..depth + common]);
+        return H::hash(&child, &path) + Felt::from(common as u64);
+    }
+
+    let split = leaves.partition_point(|(key, _)| !key[depth]);
+    H::hash(
+        &subtree::<H>(&leaves[..split], depth + 1),
+        &subtree::<H>(&leaves[split..], depth + 1),
+    )
+}
+
+/// Number of bits from `depth` shared by all of the sorted `leaves`.
+fn common_prefix(leaves: &[Leaf], depth: usize) -> usize {
+    let first = &leaves[0].0;
+    let last = &leaves[leaves.len() - 1].0;
+    (depth..HEIGHT).take_while(|&i| first[i] == last[i]).count()
+}
+
+/// A node of the trie [`root`] hashes. `path` holds the bits from the root down to the
+/// node, as a string of `0` and `1`, and hashes are hex.
+#[cfg(feature = "node-export")]
+#[derive(serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Node {
+    Binary {
+        path: String,
+        hash: String,
+        left: String,
+        right: String,
+    },
+    Edge {
+        path: String,
+        hash: String,
+        /// Bits below `path` skipped by the edge
+        edge: String,
+        child: String,
+    },
+    Leaf {
+        path: String,
+        value: String,
+    },
+}
+
+/// Every node of the trie [`root`] hashes for `storage`, children before their parent,
+/// so that the last node is the root. Fails once more than `limit` nodes are found.
+#[cfg(feature = "node-export")]
+pub fn nodes<'a>(
+    storage: impl IntoIterator<Item = (&'a FieldElement, &'a FieldElement)>,
+    limit: usize,
+) -> anyhow::Result<Vec<Node>> {
+    let leaves = leaves(storage, false);
+    let mut nodes = vec![];
+    if !leaves.is_empty() {
+        export_subtree(&leaves, 0, limit, &mut nodes)?;
+    }
+    Ok(nodes)
+}
+
+#[cfg(feature = "node-export")]
+fn export_subtree(
+    leaves: &[Leaf],
+    depth: usize,
+    limit: usize,
+    nodes: &mut Vec<Node>,
+) -> anyhow::Result<Felt> {
+    let bits = |bits: &BitSlice<u8, Msb0>| -> String {
+        bits.iter()
+            .map(|bit| if *bit { '1' } else { '0' })
+            .collect()
+    };
+    let path = bits(&leaves[0].0[..depth]);
+
+    if depth == HEIGHT {
+        let value = leaves[0].1;
+        let leaf = Node::Leaf {
+            path,
+            value: format!("{value:#x}"),
+        };
+        push_node(nodes, leaf, limit)?;
+        return Ok(value);
+    }
+
+    let common = common_prefix(leaves, depth);
+    let (hash, node) = if common > 0 {
+        let child = export_subtree(leaves, depth + common, limit, nodes)?;
+        let edge = &leaves[0].0[depth..depth + common];
+        let hash = Pedersen::hash(&child, &path_felt(edge)) + Felt::from(common as u64);
+        let node = Node::Edge {
+            path,
+            hash: format!("{hash:#x}"),
+            edge: bits(edge),
+            child: format!("{child:#x}"),
+        };
+        (hash, node)
+    } else {
+        let split = leaves.partition_point(|(key, _)| !key[depth]);
+        let left = export_subtree(&leaves[..split], depth + 1, limit, nodes)?;
+        let right = export_subtree(&leaves[split..], depth + 1, limit, nodes)?;
+        let hash = Pedersen::hash(&left, &right);
+        let node = Node::Binary {
+            path,
+            hash: format!("{hash:#x}"),
+            left: format!("{left:#x}"),
+            right: format!("{right:#x}"),
+        };
+        (hash, node)
+    };
+
+    push_node(nodes, node, limit)?;
+    Ok(hash)
+}
+
+#[cfg(feature = "node-export")]
+fn push_node(nodes: &mut Vec<Node>, node: Node, limit: usize) -> anyhow::Result<()> {
+    if nodes.len() == limit {
+        return Err(anyhow::anyhow!("The trie has more than {limit} nodes"));
+    }
+    nodes.push(node);
+    Ok(())
+}
+
+/// Interprets `bits` as a big-endian integer.
+fn path_felt(bits: &BitSlice<u8, Msb0>) -> Felt {
+    let mut bytes = [0u8; 32];
+    bytes.view_bits_mut::<Msb0>()[256 - bits.len()..].copy_from_bitslice(bits);
+    Felt::from_bytes_be(&bytes)
+}
@@ -0,0 +1,210 @@
+//! Per-block results of a run, read back to compare two runs, e.g. before and after a
+//! dependency bump.
+//!
+//! Both result files the tool writes are understood: the JSON lines of --jsonl-output,
+//! which also tell whether each root matched the reference trie, and the golden files of
+//! --write-golden, which only hold roots.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use starknet::core::types::FieldElement;
+
+use crate::golden;
+
+/// What a run computed at one block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    pub root: FieldElement,
+    /// Whether the reference trie agreed, unknown for golden files and runs without
+    /// --reference-check
+    pub matched: Option<bool>,
+}
+
+pub type Results = BTreeMap<u64, Outcome>;
+
+/// One line of --jsonl-output, the fields not compared are ignored.
+#[derive(Deserialize)]
+struct Line {
+    block: u64,
+    root: FieldElement,
+    #[serde(rename = "match")]
+    matched: Option<bool>,
+}
+
+/// Reads a golden file, or the JSON lines of --jsonl-output if `path` is not one.
+pub fn read(path: &Path) -> anyhow::Result<Results> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read result file {}", path.display()))?;
+
+    if let Ok(roots) = serde_json::from_str::<golden::Roots>(&content) {
+        return Ok(roots
+            .into_iter()
+            .map(|(block, root)| {
+                let outcome = Outcome {
+                    root,
+                    matched: None,
+                };
+                (block, outcome)
+            })
+            .collect());
+    }
+
+    let mut results = Results::new();
+    for (number, line) in (1..).zip(content.lines()) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line: Line = serde_json::from_str(line).with_context(|| {
+            format!(
+                "{}:{number} is neither a golden file nor --jsonl-output",
+                path.display()
+            )
+        })?;
+        let outcome = Outcome {
+            root: line.root,
+            matched: line.matched,
+        };
+        if results.insert(line.block, outcome).is_some() {
+            return Err(anyhow!(
+                "{}:{number} repeats block {}, a result file covers a single contract",
+                path.display(),
+                line.block
+            ));
+        }
+    }
+
+    Ok(results)
+}
+
+/// A block on which two runs differ.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// Only one run computed a root at `block`
+    Missing {
+        block: u64,
+        left: Option<Outcome>,
+        right: Option<Outcome>,
+    },
+    /// Both runs computed a root at `block`, they differ in root or match status. A match
+    /// status unknown to either run is not compared
+    Changed {
+        block: u64,
+        left: Outcome,
+        right: Outcome,
+    },
+}
+
+/// Blocks on which `left` and `right` differ, in block order.
+pub fn diff(left: &Results, right: &Results) -> Vec<Difference> {
+    let mut blocks: Vec<_> = left.keys().chain(right.keys()).copied().collect();
+    blocks.sort_unstable();
+    blocks.dedup();
+
+    blocks
+        .into_iter()
+        .filter_map(|block| match (left.get(&block), right.get(&block)) {
+            (Some(&left), Some(&right)) => {
+                let status_changed = matches!(
+                    (left.matched, right.matched),
+                    (Some(left), Some(right)) if left != right
+                );
+                (left.root != right.root || status_changed).then_some(Difference::Changed {
+                    block,
+                    left,
+                    right,
+                })
+            }
+            (left, right) => Some(Difference::Missing {
+                block,
+                left: left.copied(),
+                right: right.copied(),
+            }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(root: u64, matched: Option<bool>) -> Outcome {
+        Outcome {
+            root: FieldElement::from(root),
+            matched,
+        }
+    }
+
+    #[test]
+    fn runs_differ_on_roots_status_and_coverage() {
+        let left = Results::from([
+            (1, outcome(10, Some(true))),
+            (2, outcome(20, Some(true))),
+            (3, outcome(30, Some(true))),
+            (4, outcome(40, None)),
+            (5, outcome(50, Some(true))),
+        ]);
+        let right = Results::from([
+            (1, outcome(10, Some(true))),
+            (2, outcome(21, Some(true))),
+            (3, outcome(30, Some(false))),
+            (4, outcome(40, Some(false))),
+            (6, outcome(60, None)),
+        ]);
+
+        assert_eq!(
+            diff(&left, &right),
+            [
+                Difference::Changed {
+                    block: 2,
+                    left: left[&2],
+                    right: right[&2],
+                },
+                Difference::Changed {
+                    block: 3,
+                    left: left[&3],
+                    right: right[&3],
+                },
+                Difference::Missing {
+                    block: 5,
+                    left: Some(left[&5]),
+                    right: None,
+                },
+                Difference::Missing {
+                    block: 6,
+                    left: None,
+                    right: Some(right[&6]),
+                },
+            ]
+        );
+        assert!(diff(&left, &left).is_empty());
+    }
+
+    #[test]
+    fn golden_files_and_json_lines_are_read_alike() {
+        let dir = tempfile::tempdir().unwrap();
+        let golden_path = dir.path().join("golden.json");
+        let roots = golden::Roots::from([(7, FieldElement::from(70u64))]);
+        golden::write(&golden_path, &roots).unwrap();
+
+        let jsonl_path = dir.path().join("results.jsonl");
+        std::fs::write(
+            &jsonl_path,
+            "{\"block\":7,\"contract\":\"0x1\",\"root\":\"0x46\",\"previous_root\":\"0x0\",\
+            \"keys\":1,\"match\":true}\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            read(&golden_path).unwrap(),
+            Results::from([(7, outcome(70, None))])
+        );
+        assert_eq!(
+            read(&jsonl_path).unwrap(),
+            Results::from([(7, outcome(70, Some(true)))])
+        );
+        assert!(diff(&read(&golden_path).unwrap(), &read(&jsonl_path).unwrap()).is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+//! Roots tagged with the engine which computed them and what they were computed for.
+//!
+//! Only roots of different engines can be compared, and only when they cover the same
+//! contract after the same block, so a comparison can't silently mix up two blocks.
+
+use anyhow::anyhow;
+use starknet::core::types::FieldElement;
+use starknet_types_core::felt::Felt;
+
+/// What a root covers: the storage of `contract` after applying `block`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RootContext {
+    pub contract: FieldElement,
+    pub block: u64,
+}
+
+/// Root computed by Bonsai.
+#[derive(Clone, Copy, Debug)]
+pub struct BonsaiRoot {
+    pub context: RootContext,
+    pub root: Felt,
+}
+
+/// Root computed by [`crate::reference`].
+#[derive(Clone, Copy, Debug)]
+pub struct ReferenceRoot {
+    pub context: RootContext,
+    pub root: Felt,
+}
+
+impl BonsaiRoot {
+    /// Whether both roots are equal, or an error if they cover different storage.
+    pub fn matches(&self, other: &ReferenceRoot) -> anyhow::Result<bool> {
+        if self.context != other.context {
+            return Err(anyhow!(
+                "Cannot compare the Bonsai root of {:#x} at block {} with the reference root \
+                of {:#x} at block {}",
+                self.context.contract,
+                self.context.block,
+                other.context.contract,
+                other.context.block
+            ));
+        }
+
+        Ok(self.root == other.root)
+    }
+}
@@ -0,0 +1,66 @@
+//! Absolute storage values read from a full node, as opposed to values accumulated from
+//! state diffs, and the node's own view of those diffs.
+//!
+//! A root built from the values `starknet_getStorageAt` reports at a block only depends on
+//! the node's state, so comparing it to the diff-accumulated root checks the accumulation
+//! itself.
+
+use anyhow::{anyhow, Context};
+use indicatif::ProgressBar;
+use starknet::core::types::{BlockId, FieldElement, MaybePendingStateUpdate};
+use starknet::providers::jsonrpc::{HttpTransport, JsonRpcClient};
+use starknet::providers::Provider;
+use url::Url;
+
+/// Fetches the value of each of `keys` in the storage of `contract_address` at `block`.
+pub async fn storage_at(
+    rpc_url: &Url,
+    contract_address: FieldElement,
+    keys: &[FieldElement],
+    block: u64,
+    bar: &ProgressBar,
+) -> anyhow::Result<Vec<(FieldElement, FieldElement)>> {
+    let client = JsonRpcClient::new(HttpTransport::new(rpc_url.clone()));
+    let block_id = BlockId::Number(block);
+
+    let mut storage = Vec::with_capacity(keys.len());
+    for key in keys {
+        let value = client
+            .get_storage_at(contract_address, key, block_id)
+            .await
+            .with_context(|| format!("Failed to get storage at {key:#x} for block {block}"))?;
+        bar.inc(1);
+        storage.push((*key, value));
+    }
+
+    Ok(storage)
+}
+
+/// Fetches the storage diff of `contract_address` in `block` with `starknet_getStateUpdate`,
+/// empty if the block does not touch the contract.
+pub async fn storage_diff(
+    rpc_url: &Url,
+    contract_address: FieldElement,
+    block: u64,
+) -> anyhow::Result<Vec<(FieldElement, FieldElement)>> {
+    let client = JsonRpcClient::new(HttpTransport::new(rpc_url.clone()));
+
+    let state_update = client
+        .get_state_update(BlockId::Number(block))
+        .await
+        .with_context(|| format!("Failed to get the state update of block {block}"))?;
+    let MaybePendingStateUpdate::Update(state_update) = state_update else {
+        return Err(anyhow!(
+            "The node only has a pending state update for block {block}"
+        ));
+    };
+
+    Ok(state_update
+        .state_diff
+        .storage_diffs
+        .into_iter()
+        .filter(|diff| diff.address == contract_address)
+        .flat_map(|diff| diff.storage_entries)
+        .map(|entry| (entry.key, entry.value))
+        .collect())
+}
@@ -0,0 +1,295 @@
+//! Step-by-step trie building, for driving an engine one key at a time and inspecting the
+//! root after each operation.
+//!
+//! Both engines implement [`StorageTrie`]. A [`BonsaiSession`] holds a live trie in a
+//! RocksDB its caller owns: every insert goes straight into it, and reading the root
+//! commits. The reference trie has no incremental mode, so a [`ReferenceSession`] keeps
+//! the raw pairs and rehashes them on demand.
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use bonsai_trie::databases::RocksDB;
+use bonsai_trie::id::{BasicId, BasicIdBuilder};
+use bonsai_trie::BonsaiStorage;
+use starknet::core::types::FieldElement;
+use starknet_types_core::felt::Felt;
+use starknet_types_core::hash::Pedersen;
+
+use crate::{reference, to_felt, trie_key, BonsaiOptions, IDENTIFIER};
+
+/// A storage trie built one key at a time.
+pub trait StorageTrie {
+    /// Sets `key` to `value` and returns the previous nonzero value. As in a run, a zero
+    /// value deletes the key, leaving it out of the root.
+    fn insert(
+        &mut self,
+        key: FieldElement,
+        value: FieldElement,
+    ) -> anyhow::Result<Option<FieldElement>>;
+
+    /// Root of everything inserted so far.
+    fn root(&mut self) -> anyhow::Result<Felt>;
+
+    /// Number of keys with a nonzero value.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Sets `key` in `storage`, where keys set to zero are absent.
+fn set(
+    storage: &mut HashMap<FieldElement, FieldElement>,
+    key: FieldElement,
+    value: FieldElement,
+) -> Option<FieldElement> {
+    if value == FieldElement::ZERO {
+        storage.remove(&key)
+    } else {
+        storage.insert(key, value)
+    }
+}
+
+/// Live Bonsai trie. It only borrows its db, so the db, and the directory holding it,
+/// must outlive the session.
+pub struct BonsaiSession<'db> {
+    trie: BonsaiStorage<BasicId, RocksDB<'db, BasicId>, Pedersen>,
+    id_builder: BasicIdBuilder,
+    storage: HashMap<FieldElement, FieldElement>,
+    /// Previous values of the keys inserted since the last commit
+    pending: Vec<(FieldElement, Option<FieldElement>)>,
+    /// Each commit, with the previous values of the keys it changed
+    journal: Vec<(BasicId, Vec<(FieldElement, Option<FieldElement>)>)>,
+}
+
+impl<'db> BonsaiSession<'db> {
+    /// Session over `db`, which must not hold a trie yet, with the storage tuning of
+    /// `options`.
+    pub fn new(db: RocksDB<'db, BasicId>, options: &BonsaiOptions) -> anyhow::Result<Self> {
+        let trie = BonsaiStorage::new(db, options.storage.config())
+            .map_err(|e| anyhow!("Failed to create Bonsai storage: {e:?}"))?;
+
+        Ok(Self {
+            trie,
+            id_builder: BasicIdBuilder::new(),
+            storage: HashMap::new(),
+            pending: Vec::new(),
+            journal: Vec::new(),
+        })
+    }
+
+    /// Commits the inserts made since the last commit under a fresh id, and returns it.
+    /// Commits even when nothing is pending, so every call gives a new id.
+    pub fn commit(&mut self) -> anyhow::Result<BasicId> {
+        let id = self.id_builder.new_id();
+        self.trie
+            .commit(id)
+            .map_err(|e| anyhow!("Failed to commit to Bonsai storage: {e:?}"))?;
+        self.journal.push((id, std::mem::take(&mut self.pending)));
+        Ok(id)
+    }
+
+    /// Whether inserts were made since the last commit.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Reverts the trie to the state it had at commit `id`, through Bonsai's own history,
+    /// dropping the commits after it and any pending insert. Fails if Bonsai no longer
+    /// keeps that far back, see `max_saved_trie_logs`.
+    pub fn revert_to(&mut self, id: BasicId) -> anyhow::Result<()> {
+        let kept = self
+            .journal
+            .iter()
+            .position(|(commit, _)| *commit == id)
+            .ok_or_else(|| anyhow!("No commit {id:?} in this session"))?
+            + 1;
+        if self.has_pending() {
+            self.commit()?;
+        }
+
+        self.trie
+            .revert_to(id)
+            .map_err(|e| anyhow!("Failed to revert Bonsai storage to {id:?}: {e:?}"))?;
+        for (_, changes) in self.journal.drain(kept..).rev() {
+            for (key, previous) in changes.into_iter().rev() {
+                set(
+                    &mut self.storage,
+                    key,
+                    previous.unwrap_or(FieldElement::ZERO),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The keys with a nonzero value and their values.
+    pub fn storage(&self) -> &HashMap<FieldElement, FieldElement> {
+        &self.storage
+    }
+}
+
+impl StorageTrie for BonsaiSession<'_> {
+    fn insert(
+        &mut self,
+        key: FieldElement,
+        value: FieldElement,
+    ) -> anyhow::Result<Option<FieldElement>> {
+        self.trie
+            .insert(IDENTIFIER, &trie_key(&key), &to_felt(&value))
+            .map_err(|e| anyhow!("Failed to insert into Bonsai storage: {e:?}"))?;
+
+        let previous = set(&mut self.storage, key, value);
+        self.pending.push((key, previous));
+        Ok(previous)
+    }
+
+    /// Commits the pending inserts first, if any, as Bonsai may only report committed
+    /// roots.
+    fn root(&mut self) -> anyhow::Result<Felt> {
+        if self.has_pending() {
+            self.commit()?;
+        }
+        self.trie
+            .root_hash(IDENTIFIER)
+            .map_err(|e| anyhow!("Failed to retrieve root hash: {e:?}"))
+    }
+
+    fn len(&self) -> usize {
+        self.storage.len()
+    }
+}
+
+/// Reference trie, rehashed from scratch on every [`StorageTrie::root`].
+#[derive(Default)]
+pub struct ReferenceSession {
+    storage: HashMap<FieldElement, FieldElement>,
+}
+
+impl ReferenceSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageTrie for ReferenceSession {
+    fn insert(
+        &mut self,
+        key: FieldElement,
+        value: FieldElement,
+    ) -> anyhow::Result<Option<FieldElement>> {
+        Ok(set(&mut self.storage, key, value))
+    }
+
+    fn root(&mut self) -> anyhow::Result<Felt> {
+        Ok(reference::root(&self.storage))
+    }
+
+    fn len(&self) -> usize {
+        self.storage.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bonsai_trie::databases::{create_rocks_db, RocksDBConfig};
+    use indicatif::ProgressBar;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::bonsai_root;
+
+    fn felt(value: u64) -> FieldElement {
+        FieldElement::from(value)
+    }
+
+    #[test]
+    fn sessions_follow_batch_roots_insert_by_insert() {
+        let tempdir = tempdir().unwrap();
+        let db = create_rocks_db(tempdir.path()).unwrap();
+        let mut bonsai = BonsaiSession::new(
+            RocksDB::new(&db, RocksDBConfig::default()),
+            &BonsaiOptions::default(),
+        )
+        .unwrap();
+        let mut reference = ReferenceSession::new();
+
+        // Overwrites, deletes of live keys and zeros written to keys never set, ending on
+        // an empty trie
+        let writes = [
+            (1, 10),
+            (2, 20),
+            (3, 30),
+            (2, 21),
+            (1, 0),
+            (4, 0),
+            (5, 50),
+            (3, 0),
+            (2, 0),
+            (5, 0),
+        ];
+        let mut storage = HashMap::new();
+        for (key, value) in writes {
+            let (key, value) = (felt(key), felt(value));
+            let previous = storage
+                .insert(key, value)
+                .filter(|previous| *previous != FieldElement::ZERO);
+            assert_eq!(bonsai.insert(key, value).unwrap(), previous);
+            assert_eq!(reference.insert(key, value).unwrap(), previous);
+
+            let expected = reference::root(&storage);
+            assert_eq!(
+                bonsai_root(&storage, &ProgressBar::hidden(), &BonsaiOptions::default()).unwrap(),
+                expected
+            );
+            assert_eq!(
+                bonsai.root().unwrap(),
+                expected,
+                "after {key:#x} = {value:#x}"
+            );
+            assert_eq!(
+                reference.root().unwrap(),
+                expected,
+                "after {key:#x} = {value:#x}"
+            );
+            assert_eq!(bonsai.len(), reference.len());
+        }
+
+        assert!(bonsai.is_empty());
+        assert_eq!(bonsai.root().unwrap(), Felt::ZERO);
+    }
+
+    #[test]
+    fn reverting_restores_an_earlier_root() {
+        let tempdir = tempdir().unwrap();
+        let db = create_rocks_db(tempdir.path()).unwrap();
+        let mut bonsai = BonsaiSession::new(
+            RocksDB::new(&db, RocksDBConfig::default()),
+            &BonsaiOptions::default(),
+        )
+        .unwrap();
+
+        let mut commits = Vec::new();
+        for (key, value) in [(1, 10), (2, 20), (1, 11), (3, 0), (2, 0)] {
+            bonsai.insert(felt(key), felt(value)).unwrap();
+            let id = bonsai.commit().unwrap();
+            commits.push((id, bonsai.root().unwrap(), bonsai.storage().clone()));
+        }
+
+        let (id, root, storage) = commits[1].clone();
+        // Left pending, the revert drops it too
+        bonsai.insert(felt(4), felt(40)).unwrap();
+        bonsai.revert_to(id).unwrap();
+        assert_eq!(bonsai.root().unwrap(), root);
+        assert_eq!(bonsai.storage(), &storage);
+
+        // The reverted trie keeps working
+        bonsai.insert(felt(5), felt(50)).unwrap();
+        let mut expected = storage;
+        expected.insert(felt(5), felt(50));
+        assert_eq!(bonsai.root().unwrap(), reference::root(&expected));
+    }
+}
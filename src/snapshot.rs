@@ -0,0 +1,114 @@
+//! Portable snapshots of the accumulated storage of a contract.
+//!
+//! A snapshot holds the raw key/value pairs rather than any engine-specific trie state,
+//! so any trie can be rehydrated from it. The root recorded at export time is checked
+//! again at load time to confirm the round-trip.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use starknet::core::types::FieldElement;
+use tokio::sync::RwLock;
+
+use crate::{bonsai_root, to_field_element, BonsaiOptions, CONTRACT_STORAGE};
+
+#[derive(Serialize, Deserialize)]
+pub struct StorageEntry {
+    pub key: FieldElement,
+    pub value: FieldElement,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub contract: FieldElement,
+    /// Last block whose diff is included
+    pub block: u64,
+    /// Bonsai root of `storage`, with no key filter or leaf transform
+    pub root: FieldElement,
+    pub storage: Vec<StorageEntry>,
+}
+
+impl Snapshot {
+    pub fn root_of(storage: &[StorageEntry]) -> anyhow::Result<FieldElement> {
+        let root = bonsai_root(
+            storage.iter().map(|entry| (&entry.key, &entry.value)),
+            &ProgressBar::hidden(),
+            &BonsaiOptions::default(),
+        )?;
+        Ok(to_field_element(&root))
+    }
+}
+
+/// Writes the accumulated storage of `contract` as of `block` to `path`.
+pub async fn export(contract: FieldElement, block: u64, path: &Path) -> anyhow::Result<Snapshot> {
+    let mut storage: Vec<StorageEntry> = match CONTRACT_STORAGE.read().await.get(&contract) {
+        Some(contract_storage) => contract_storage
+            .read()
+            .await
+            .iter()
+            .map(|(key, value)| StorageEntry {
+                key: *key,
+                value: *value,
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    storage.sort_by_key(|entry| entry.key);
+
+    let snapshot = Snapshot {
+        contract,
+        block,
+        root: Snapshot::root_of(&storage)?,
+        storage,
+    };
+
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create snapshot file {}", path.display()))?;
+    serde_json::to_writer_pretty(BufWriter::new(file), &snapshot)
+        .with_context(|| format!("Failed to write snapshot to {}", path.display()))?;
+
+    Ok(snapshot)
+}
+
+/// Reads a snapshot from `path` and checks its storage still hashes to the recorded root.
+pub fn read(path: &Path) -> anyhow::Result<Snapshot> {
+    let snapshot = read_unchecked(path)?;
+
+    let root = Snapshot::root_of(&snapshot.storage)?;
+    if root != snapshot.root {
+        return Err(anyhow!(
+            "Snapshot {} records root {:#x} but its storage hashes to {root:#x}",
+            path.display(),
+            snapshot.root
+        ));
+    }
+
+    Ok(snapshot)
+}
+
+/// Same as [`read`], without checking the recorded root.
+pub fn read_unchecked(path: &Path) -> anyhow::Result<Snapshot> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open snapshot file {}", path.display()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to parse snapshot {}", path.display()))
+}
+
+/// Replaces the accumulated storage of the snapshot's contract with the snapshot's.
+pub async fn restore(snapshot: &Snapshot) {
+    let storage: HashMap<FieldElement, FieldElement> = snapshot
+        .storage
+        .iter()
+        .map(|entry| (entry.key, entry.value))
+        .collect();
+
+    CONTRACT_STORAGE
+        .write()
+        .await
+        .insert(snapshot.contract, RwLock::new(storage));
+}